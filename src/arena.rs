@@ -0,0 +1,159 @@
+pub mod arena {
+    use crate::globals::globals::*;
+
+    pub const GC_MIN_THRESHOLD: usize = 64;
+
+    struct Slot {
+        generation: u32,
+        name: Option<String>,
+        limits: StackLimits,
+        stack: Option<Stack>
+    }
+
+    #[derive(Debug, Clone, Default)]
+    pub struct GcStats {
+        pub collections: usize,
+        pub reclaimed: usize,
+        pub peak: usize
+    }
+
+    /// Owns every spawned stack. Slots of closed stacks are reused, with a new
+    /// generation so that old handles to them stay dead.
+    pub struct StackArena {
+        slots: Vec<Slot>,
+        free: Vec<usize>,
+        threshold: usize,
+        pub stats: GcStats
+    }
+
+    impl StackArena {
+        pub fn new() -> Self {
+            StackArena {
+                slots: Vec::new(),
+                free: Vec::new(),
+                threshold: GC_MIN_THRESHOLD,
+                stats: GcStats::default()
+            }
+        }
+
+        pub fn insert(&mut self, stack: Stack, name: Option<String>) -> StackHandle {
+            let handle = if let Some(index) = self.free.pop() {
+                let slot = &mut self.slots[index];
+                slot.name = name;
+                slot.limits = StackLimits::default();
+                slot.stack = Some(stack);
+                StackHandle {index, generation: slot.generation}
+            } else {
+                self.slots.push(Slot {generation: 0, name, limits: StackLimits::default(), stack: Some(stack)});
+                StackHandle {index: self.slots.len() - 1, generation: 0}
+            };
+
+            self.stats.peak = self.stats.peak.max(self.len());
+            handle
+        }
+
+        /// The number of stacks that are still open.
+        pub fn len(&self) -> usize {
+            self.slots.len() - self.free.len()
+        }
+
+        pub fn contains(&self, handle: StackHandle) -> bool {
+            self.get(handle).is_some()
+        }
+
+        pub fn name(&self, handle: StackHandle) -> Option<&String> {
+            match self.slots.get(handle.index) {
+                Some(slot) if slot.generation == handle.generation && slot.stack.is_some() => slot.name.as_ref(),
+                _ => None
+            }
+        }
+
+        pub fn limits(&self, handle: StackHandle) -> Option<&StackLimits> {
+            match self.slots.get(handle.index) {
+                Some(slot) if slot.generation == handle.generation && slot.stack.is_some() => Some(&slot.limits),
+                _ => None
+            }
+        }
+
+        pub fn set_limits(&mut self, handle: StackHandle, limits: StackLimits) {
+            if let Some(slot) = self.slots.get_mut(handle.index) {
+                if slot.generation == handle.generation {
+                    slot.limits = limits;
+                }
+            }
+        }
+
+        pub fn get(&self, handle: StackHandle) -> Option<&Stack> {
+            match self.slots.get(handle.index) {
+                Some(slot) if slot.generation == handle.generation => slot.stack.as_ref(),
+                _ => None
+            }
+        }
+
+        pub fn get_mut(&mut self, handle: StackHandle) -> Option<&mut Stack> {
+            match self.slots.get_mut(handle.index) {
+                Some(slot) if slot.generation == handle.generation => slot.stack.as_mut(),
+                _ => None
+            }
+        }
+
+        pub fn remove(&mut self, handle: StackHandle) -> Option<Stack> {
+            let slot = self.slots.get_mut(handle.index)?;
+            if slot.generation != handle.generation || slot.stack.is_none() {
+                return None;
+            }
+
+            slot.generation = slot.generation.wrapping_add(1);
+            slot.name = None;
+            self.free.push(handle.index);
+            slot.stack.take()
+        }
+
+        /// Whether enough stacks have been opened since the last collection
+        /// to make another one worthwhile.
+        pub fn needs_collection(&self) -> bool {
+            self.len() >= self.threshold
+        }
+
+        /// Finds every stack that can be reached from `roots`, following the
+        /// pointers stored on reachable stacks. Indexed by slot.
+        pub fn mark(&self, roots: Vec<StackHandle>) -> Vec<bool> {
+            let mut marked = vec![false; self.slots.len()];
+            let mut pending = roots;
+            while let Some(handle) = pending.pop() {
+                if marked.get(handle.index) == Some(&false) {
+                    if let Some(stack) = self.get(handle) {
+                        marked[handle.index] = true;
+                        for value in stack.iter() {
+                            if let DataTypes::STACKPOINTER(p) = value {
+                                pending.push(*p);
+                            }
+                        }
+                    }
+                }
+            }
+            marked
+        }
+
+        pub fn is_marked(&self, marked: &Vec<bool>, handle: StackHandle) -> bool {
+            self.contains(handle) && marked[handle.index]
+        }
+
+        /// Closes every stack `mark` didn't reach. Returns how many were closed.
+        pub fn sweep(&mut self, marked: Vec<bool>) -> usize {
+            let mut reclaimed = 0;
+            for index in 0..self.slots.len() {
+                if !marked[index] && self.slots[index].stack.is_some() {
+                    let generation = self.slots[index].generation;
+                    self.remove(StackHandle {index, generation});
+                    reclaimed += 1;
+                }
+            }
+
+            self.threshold = (self.len() * 2).max(GC_MIN_THRESHOLD);
+            self.stats.collections += 1;
+            self.stats.reclaimed += reclaimed;
+            reclaimed
+        }
+    }
+}
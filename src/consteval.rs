@@ -0,0 +1,111 @@
+pub mod consteval {
+    use crate::globals::globals::*;
+    use crate::interpreter::program::Program;
+    use std::collections::HashMap;
+
+    /// How many instructions evaluating a single call may take before it is
+    /// left to run normally instead.
+    pub const FOLD_BUDGET: usize = 100000;
+
+    /// Replaces every call to a 'const procedure' whose arguments are pushed
+    /// literals with the values the call leaves on the stack.
+    pub struct Folder {
+        procedures: HashMap<String, ProcedureDefine>,
+        file: String
+    }
+
+    impl Folder {
+        pub fn new(file: String) -> Self {
+            Folder {
+                procedures: HashMap::new(),
+                file
+            }
+        }
+
+        pub fn fold(&mut self, instructions: Vec<Option<Instruction>>) -> Vec<Option<Instruction>> {
+            let mut res: Vec<Option<Instruction>> = Vec::new();
+
+            for instruction in instructions {
+                let mut instr = match instruction {
+                    Some(instr) => instr,
+                    None => {
+                        res.push(None);
+                        continue;
+                    }
+                };
+
+                match &mut instr.Instruction {
+                    Instructions::IDENTIFIER(name) | Instructions::TAILPOSITION(name) => {
+                        if let Some(values) = self.fold_call(name.to_string(), &mut res, &instr) {
+                            for value in values {
+                                res.push(Some(Instruction::new(Instructions::PUSH(value), instr.line_num, instr.file_name.to_string())));
+                            }
+                            continue;
+                        }
+                    },
+                    Instructions::PROCEDURE(nested_struct) => {
+                        // Registered before its body is folded so that it may call itself
+                        if nested_struct.pure {
+                            self.procedures.insert(nested_struct.name.to_string(), nested_struct.clone());
+                        }
+                        let body = std::mem::take(&mut nested_struct.instructions).into_iter().map(Some).collect();
+                        nested_struct.instructions = self.fold(body).into_iter().flatten().collect();
+                        if nested_struct.pure {
+                            self.procedures.insert(nested_struct.name.to_string(), nested_struct.clone());
+                        }
+                    },
+                    Instructions::If(nested_struct) => {
+                        nested_struct.If = nested_struct.If.take().map(|block| self.fold(block));
+                        nested_struct.Else = nested_struct.Else.take().map(|block| self.fold(block));
+                    },
+                    Instructions::While(nested_struct) => {
+                        nested_struct.Cond = self.fold(std::mem::take(&mut nested_struct.Cond));
+                        nested_struct.Contents = self.fold(std::mem::take(&mut nested_struct.Contents));
+                    },
+                    Instructions::EACH(nested_struct) => {
+                        nested_struct.source = self.fold(std::mem::take(&mut nested_struct.source));
+                        nested_struct.contents = self.fold(std::mem::take(&mut nested_struct.contents));
+                    },
+                    Instructions::VARDECLARE(nested_struct) => {
+                        nested_struct.instructions = self.fold(std::mem::take(&mut nested_struct.instructions));
+                    },
+                    Instructions::LET(nested_struct) => {
+                        nested_struct.instructions = self.fold(std::mem::take(&mut nested_struct.instructions));
+                    },
+                    Instructions::MACRO(nested_struct) => {
+                        nested_struct.instructions = self.fold(std::mem::take(&mut nested_struct.instructions));
+                    },
+                    Instructions::DEFER(nested_instructions) | Instructions::LITERAL(nested_instructions) | Instructions::IMPORT(nested_instructions) => {
+                        *nested_instructions = self.fold(std::mem::take(nested_instructions));
+                    },
+                    _ => ()
+                }
+                res.push(Some(instr));
+            }
+            res
+        }
+
+        /// Evaluates a call to `name` if it is a pure procedure and the
+        /// instructions just before it push all of its arguments, removing
+        /// those pushes from `res`.
+        fn fold_call(&self, name: String, res: &mut Vec<Option<Instruction>>, instruction: &Instruction) -> Option<Vec<u8>> {
+            let procedure = self.procedures.get(&name)?;
+            let argc = procedure.args.len();
+            if res.len() < argc || !res[res.len() - argc..].iter().all(|i| matches!(i, Some(Instruction {Instruction: Instructions::PUSH(_), ..}))) {
+                return None;
+            }
+
+            let mut program = res[res.len() - argc..].to_vec();
+            program.push(Some(Instruction::new(Instructions::IDENTIFIER(name), instruction.line_num, instruction.file_name.to_string())));
+
+            // A body that would fail here, such as one taking more values than its
+            // arguments, or that runs too long, is left to run on the caller's stack
+            let values = Program::evaluate(program, self.procedures.clone(), self.file.clone(), Some(FOLD_BUDGET))?;
+            res.truncate(res.len() - argc);
+            Some(values.into_iter().map(|value| match value {
+                DataTypes::INT(value) => value,
+                _ => unreachable!()
+            }).collect())
+        }
+    }
+}
@@ -3,6 +3,7 @@ pub mod globals {
     use crate::globals::colorize::*;
 
     use std::fs::File;
+    use std::collections::VecDeque;
     use std::io::prelude::*;
 
     #[derive(Debug, Clone, PartialEq)]
@@ -23,6 +24,9 @@ pub mod globals {
         IF,
         ELSE,
         WHILE,
+        EACH,
+        EACHREV,
+        AS,
         END,
         DO,
         VARDECLARE(String), // Begin variable declaration
@@ -30,18 +34,49 @@ pub mod globals {
         DROP,
         IDENTIFIER(String), // Identifier
         SPAWN(String), // Begin spawnable stacks
+        SPAWNLOCAL(String),
+        COLON,
+        CAP,
         SWITCH,
         CLOSE,
         STACK(String),
         THIS,
         STACKS,
+        DUMP,
         STACKSIZE, 
         STACKREV,
-        STRING(Vec<Option<Operation>>), // String literal
+        MOVE,
+        COPY,
+        PEEK,
+        POKE,
+        LEN,
+        CLONE,
+        CONCAT,
+        STACKEQ,
+        SORT,
+        SLICE,
+        PUSHFRONT,
+        POPFRONT,
+        PEEKFRONT,
+        SAVESTACK,
+        LOADSTACK,
+        STRING(Vec<u8>), // String literal
+        LBRACE, // Stack literal
+        RBRACE,
         PROCEDURE, // Begin procedure
         IN,
         RETURN,
+        DEFER,
+        LET,
+        CONST,
+        MACRO,
         IMPORT(Vec<Option<Operation>>, String), // Begin import
+        GO, // Begin green threads
+        JOIN,
+        CHAN,
+        SEND,
+        RECV,
+        YIELD,
         EXIT 
     }
 
@@ -63,26 +98,94 @@ pub mod globals {
         VARDECLARE(VariableDefine),
         DROP(String),
         IDENTIFIER(String),
+        TAILPOSITION(String),
         If(IfElse),
         While(While),
-        SPAWN(String),
+        EACH(Each),
+        SPAWN(String, StackLimits),
+        SPAWNLOCAL(String, StackLimits),
         SWITCH,
         CLOSE,
         STACK(String),
         THIS,
         STACKS,
+        DUMP,
         STACKSIZE,
         STACKREV,
-        STRING(Vec<Option<Instruction>>),
+        MOVE,
+        COPY,
+        PEEK,
+        POKE,
+        LEN,
+        CLONE,
+        CONCAT,
+        STACKEQ,
+        SORT,
+        SLICE,
+        PUSHFRONT,
+        POPFRONT,
+        PEEKFRONT,
+        SAVESTACK,
+        LOADSTACK,
+        STRING(Vec<u8>),
+        LITERAL(Vec<Option<Instruction>>),
         PROCEDURE(ProcedureDefine),
+        RETURN,
+        DEFER(Vec<Option<Instruction>>),
+        LET(LetBinding),
+        BINDING(String, usize),
+        CONST(String, u8),
+        DEFMACRO(String),
+        MACRO(Macro),
         IMPORT(Vec<Option<Instruction>>),
+        GO(String),
+        JOIN,
+        CHAN,
+        SEND,
+        RECV,
+        YIELD,
         EXIT
     }
 
     #[derive(Debug, Clone, PartialEq)]
     pub enum DataTypes {
         INT(u8),
-        STACKPOINTER(*mut Vec<DataTypes>),
+        STACKPOINTER(StackHandle),
+    }
+
+    /// The variant of a `DataTypes` value, without the value itself.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum DataKind {
+        INT,
+        STACKPOINTER
+    }
+
+    impl DataTypes {
+        pub fn kind(&self) -> DataKind {
+            match self {
+                DataTypes::INT(_) => DataKind::INT,
+                DataTypes::STACKPOINTER(_) => DataKind::STACKPOINTER
+            }
+        }
+    }
+
+    /// What a spawned stack may hold, given by ': <type>' and 'cap <n>'.
+    /// Stacks without limits accept any number of values of either type.
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct StackLimits {
+        pub kind: Option<DataKind>,
+        pub capacity: Option<usize>
+    }
+
+    /// Double-ended, so that a stack can also be used as a queue.
+    pub type Stack = VecDeque<DataTypes>;
+
+    /// Refers to a stack in the interpreter's arena. The generation tells a
+    /// handle to a closed stack apart from one to a stack reusing its slot.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct StackHandle {
+        pub index: usize,
+        pub generation: u32
     }
 
     #[derive(Debug, Clone, PartialEq)]
@@ -90,7 +193,9 @@ pub mod globals {
         pub name: String,
         pub args: Vec<String>,
         pub instructions: Vec<Instruction>,
-        pub returns: bool
+        pub returns: bool,
+        pub pure: bool,
+        pub generator: bool
     }
 
     #[derive(Debug, Clone, PartialEq)]
@@ -161,13 +266,34 @@ pub mod globals {
         pub instructions: Vec<Option<Instruction>>
     }
 
+    /// Names popped off the stack by 'let', visible only inside `instructions`.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct LetBinding {
+        pub names: Vec<String>,
+        pub instructions: Vec<Option<Instruction>>
+    }
+
+    /// A loop over the elements of the stack `source` points to, binding each
+    /// one to `name`. Runs bottom to top, or top to bottom when `reverse` is set.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Each {
+        pub name: String,
+        pub source: Vec<Option<Instruction>>,
+        pub contents: Vec<Option<Instruction>>,
+        pub reverse: bool
+    }
+
+    /// An expanded use of a macro. `file_name` and `line_num` point at the
+    /// macro's definition, the enclosing instruction at the use site.
     #[derive(Debug, Clone, PartialEq)]
     pub struct Macro {
         pub name: String,
-        pub instructions: Vec<Option<Instruction>>
+        pub instructions: Vec<Option<Instruction>>,
+        pub file_name: String,
+        pub line_num: u8
     }
 
-    pub const RESERVED_KEYWORDS: [&str; 21] = [
+    pub const RESERVED_KEYWORDS: [&str; 52] = [
         "print",
         "print_ascii",
         "pop",
@@ -180,7 +306,11 @@ pub mod globals {
         "if",
         "else",
         "while",
+        "each",
+        "each_rev",
+        "as",
         "spawn",
+        "local",
         "switch",
         "stack",
         "this",
@@ -188,7 +318,34 @@ pub mod globals {
         "stack_rev",
         "stack_size",
         "close",
-        "macro"
+        "move",
+        "copy",
+        "peek",
+        "poke",
+        "len",
+        "clone",
+        "concat",
+        "stack_eq",
+        "sort",
+        "slice",
+        "push_front",
+        "pop_front",
+        "peek_front",
+        "macro",
+        "defer",
+        "return",
+        "let",
+        "const",
+        "cap",
+        "save_stack",
+        "load_stack",
+        "dump",
+        "go",
+        "join",
+        "chan",
+        "send",
+        "recv",
+        "yield"
     ];
 
     pub fn report_err(message: &str, file: &str, line_num: u8) -> ! {
@@ -245,19 +402,52 @@ pub mod fmt {
                 Instructions::VARDECLARE(x) => write!(f, "{}\n", x),
                 Instructions::DROP(x) => write!(f, "DROP {}\n", x),
                 Instructions::IDENTIFIER(x) => write!(f, "IDENTIFIER {:?}\n", x),
+                Instructions::TAILPOSITION(x) => write!(f, "TAILPOSITION {:?}\n", x),
                 Instructions::If(x) => write!(f, "IF {:?}\n", x),
                 Instructions::While(x) => write!(f, "{}", x),
-                Instructions::SPAWN(x) => write!(f, "SPAWN {:?}\n", x),
+                Instructions::EACH(x) => write!(f, "{}", x),
+                Instructions::SPAWN(x, limits) => write!(f, "SPAWN {:?}{}\n", x, limits),
+                Instructions::SPAWNLOCAL(x, limits) => write!(f, "SPAWNLOCAL {:?}{}\n", x, limits),
                 Instructions::SWITCH => write!(f, "SWITCH\n"),
                 Instructions::CLOSE => write!(f, "CLOSE\n"),
                 Instructions::STACK(x) => write!(f, "STACK {:?}\n", x),
                 Instructions::THIS => write!(f, "THIS\n"),
                 Instructions::STACKS => write!(f, "STACKS\n"),
+                Instructions::DUMP => write!(f, "DUMP\n"),
                 Instructions::STACKSIZE => write!(f, "STACKSIZE\n"),
                 Instructions::STACKREV => write!(f, "STACKREV\n"),
-                Instructions::STRING(x) => write!(f, "STRING {}\n", pretty_print_instructions(x.to_vec())),
+                Instructions::MOVE => write!(f, "MOVE\n"),
+                Instructions::COPY => write!(f, "COPY\n"),
+                Instructions::PEEK => write!(f, "PEEK\n"),
+                Instructions::POKE => write!(f, "POKE\n"),
+                Instructions::LEN => write!(f, "LEN\n"),
+                Instructions::CLONE => write!(f, "CLONE\n"),
+                Instructions::CONCAT => write!(f, "CONCAT\n"),
+                Instructions::STACKEQ => write!(f, "STACKEQ\n"),
+                Instructions::SORT => write!(f, "SORT\n"),
+                Instructions::SLICE => write!(f, "SLICE\n"),
+                Instructions::PUSHFRONT => write!(f, "PUSHFRONT\n"),
+                Instructions::POPFRONT => write!(f, "POPFRONT\n"),
+                Instructions::PEEKFRONT => write!(f, "PEEKFRONT\n"),
+                Instructions::SAVESTACK => write!(f, "SAVESTACK\n"),
+                Instructions::LOADSTACK => write!(f, "LOADSTACK\n"),
+                Instructions::STRING(x) => write!(f, "STRING \"{}\"\n", x.escape_ascii()),
+                Instructions::LITERAL(x) => write!(f, "LITERAL\n{}ENDLITERAL\n", pretty_print_instructions(x.to_vec())),
                 Instructions::PROCEDURE(x) => {write!(f, "{}\n", x)},
+                Instructions::RETURN => write!(f, "RETURN\n"),
+                Instructions::DEFER(x) => write!(f, "DEFER\n{}ENDDEFER\n", pretty_print_instructions(x.to_vec())),
+                Instructions::LET(x) => write!(f, "{}", x),
+                Instructions::BINDING(x, slot) => write!(f, "BINDING {:?} {}\n", x, slot),
+                Instructions::CONST(x, value) => write!(f, "CONST {} {}\n", x, value),
+                Instructions::DEFMACRO(x) => write!(f, "DEFMACRO {}\n", x),
+                Instructions::MACRO(x) => write!(f, "{}", x),
                 Instructions::IMPORT(x) => write!(f, "IMPORT {:?}\n{}ENDIMPORT\n\n", x.to_vec().into_iter().next().unwrap().unwrap().file_name, pretty_print_instructions(x.to_vec())),
+                Instructions::GO(x) => write!(f, "GO {:?}\n", x),
+                Instructions::JOIN => write!(f, "JOIN\n"),
+                Instructions::CHAN => write!(f, "CHAN\n"),
+                Instructions::SEND => write!(f, "SEND\n"),
+                Instructions::RECV => write!(f, "RECV\n"),
+                Instructions::YIELD => write!(f, "YIELD\n"),
                 Instructions::EXIT => write!(f, "EXIT\n")
             }
         }
@@ -271,6 +461,12 @@ pub mod fmt {
 
     impl Display for ProcedureDefine {
         fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            if self.pure {
+                write!(f, "CONST ")?;
+            }
+            if self.generator {
+                write!(f, "GENERATOR ")?;
+            }
             write!(f, "PROCEDURE {}", self.name)?;
             for arg in self.args.iter() {
                 write!(f, "\nARG {}\n", arg)?;
@@ -282,6 +478,26 @@ pub mod fmt {
         }
     }
 
+    impl Display for Macro {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "MACRO {} ({}:{})\n", self.name, self.file_name, self.line_num)?;
+            for instruction in self.instructions.iter() {
+                write!(f, "{}", instruction.clone().unwrap())?;
+            }
+            write!(f, "ENDMACRO\n")
+        }
+    }
+
+    impl Display for LetBinding {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "LET {}\n", self.names.join(" "))?;
+            for instruction in self.instructions.iter() {
+                write!(f, "{}", instruction.clone().unwrap())?;
+            }
+            write!(f, "ENDLET\n")
+        }
+    }
+
     impl Display for VariableDefine {
         fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
             write!(f, "VARIABLE {}\n", self.name)?;
@@ -292,6 +508,20 @@ pub mod fmt {
         }
     }
 
+    impl Display for Each {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{} {}\n", if self.reverse { "EACHREV" } else { "EACH" }, self.name)?;
+            for instruction in self.source.iter() {
+                write!(f, "{}", instruction.clone().unwrap())?;
+            }
+            write!(f, "DO\n")?;
+            for instruction in self.contents.iter() {
+                write!(f, "{}", instruction.clone().unwrap())?;
+            }
+            write!(f, "ENDEACH\n")
+        }
+    }
+
     impl Display for While {
         fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
             for instruction in self.Cond.iter() {
@@ -304,6 +534,33 @@ pub mod fmt {
         }
     }
 
+    impl Display for DataKind {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self {
+                DataKind::INT => write!(f, "int"),
+                DataKind::STACKPOINTER => write!(f, "ptr")
+            }
+        }
+    }
+
+    impl Display for StackLimits {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            if let Some(kind) = self.kind {
+                write!(f, " : {}", kind)?;
+            }
+            if let Some(capacity) = self.capacity {
+                write!(f, " cap {}", capacity)?;
+            }
+            Ok(())
+        }
+    }
+
+    impl Display for StackHandle {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "<stack {}:{}>", self.index, self.generation)
+        }
+    }
+
     pub fn pretty_print_instructions(instructions: Vec<Option<Instruction>>) -> String {
         let mut res = String::new();
         for instr in instructions {
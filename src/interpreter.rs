@@ -1,7 +1,11 @@
 pub mod program {
     use crate::globals::globals::*;
-    use std::collections::HashMap;
-    use rand::{Rng, distributions::Alphanumeric};
+    use crate::globals::colorize::*;
+    use crate::arena::arena::StackArena;
+    use crate::persist::persist;
+    use std::collections::{HashMap, VecDeque};
+    use std::io::{self, Error, ErrorKind};
+    use std::rc::Rc;
 
     #[derive(Debug, Clone, PartialEq)]
     pub enum StorageTypes {
@@ -10,394 +14,1504 @@ pub mod program {
         Procedure
     }
 
+    pub const DEFAULT_MAX_DEPTH: usize = 10000;
+
+    /// How many of a stack's top values 'stacks' shows.
+    pub const STACKS_PREVIEW: usize = 5;
+
+    /// How many instructions a green thread runs before the next one gets a turn.
+    pub const THREAD_SLICE: usize = 100;
+
+    /// What a block of instructions was entered for, and so what happens once
+    /// it runs out.
+    #[derive(Debug, Clone)]
+    pub enum BlockKind {
+        Plain,
+        WhileCond(Rc<Loop>),
+        WhileBody(Rc<Loop>),
+        EachSource(Rc<EachLoop>),
+        /// The stack being walked, how many of its elements have been visited
+        /// and how many bindings the frame had before the loop.
+        EachBody(Rc<EachLoop>, StackHandle, usize, usize),
+        Assign(Instruction),
+        Let(usize),
+        Expansion(Instruction),
+        Defer,
+        /// The stack a literal is being built on, and the one to switch back
+        /// to once it is done.
+        Literal(Instruction, StackHandle, StackHandle)
+    }
+
+    /// A running 'while' loop, shared between its condition and body blocks.
+    #[derive(Debug, Clone)]
+    pub struct Loop {
+        pub instruction: Instruction,
+        pub cond: Rc<Vec<Option<Instruction>>>,
+        pub contents: Rc<Vec<Option<Instruction>>>
+    }
+
+    /// A running 'each' loop, shared between its iterations.
+    #[derive(Debug, Clone)]
+    pub struct EachLoop {
+        pub instruction: Instruction,
+        pub contents: Rc<Vec<Option<Instruction>>>,
+        pub reverse: bool
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct Block {
+        pub instructions: Rc<Vec<Option<Instruction>>>,
+        pub index: usize,
+        pub kind: BlockKind
+    }
+
+    impl Block {
+        pub fn new(instructions: Rc<Vec<Option<Instruction>>>, kind: BlockKind) -> Self {
+            Block {
+                instructions,
+                index: 0,
+                kind
+            }
+        }
+    }
+
+    /// A single procedure invocation, holding its arguments and local
+    /// variables. The bottom frame runs the top-level program and has no
+    /// procedure; its variables are the globals in `Program::data_stack`.
+    /// `stack` is the stack the body runs on and `return_stack` the caller's,
+    /// switched back to on return. Pure procedures have neither.
+    #[derive(Debug, Clone)]
+    pub struct Frame {
+        pub procedure: Option<ProcedureDefine>,
+        pub call_site: Option<Instruction>,
+        pub stack: Option<StackHandle>,
+        pub return_stack: Option<StackHandle>,
+        pub local_stacks: HashMap<String, StackHandle>,
+        pub locals: HashMap<String, DataTypes>,
+        pub bindings: Vec<DataTypes>,
+        pub blocks: Vec<Block>,
+        pub defers: Vec<Vec<Option<Instruction>>>,
+        pub tail_call: Option<(ProcedureDefine, Vec<DataTypes>)>
+    }
+
+    impl Frame {
+        pub fn new(procedure: Option<ProcedureDefine>, call_site: Option<Instruction>, stack: Option<StackHandle>, return_stack: Option<StackHandle>, body: Vec<Option<Instruction>>) -> Self {
+            Frame {
+                procedure,
+                call_site,
+                stack,
+                return_stack,
+                local_stacks: HashMap::new(),
+                locals: HashMap::new(),
+                bindings: Vec::new(),
+                blocks: vec![Block::new(Rc::new(body), BlockKind::Plain)],
+                defers: Vec::new(),
+                tail_call: None
+            }
+        }
+
+        /// Binds popped argument values to parameter names. The first parameter
+        /// takes the value that was on top of the stack.
+        pub fn bind(&mut self, args: &Vec<String>, values: Vec<DataTypes>) {
+            for (arg, value) in args.iter().zip(values) {
+                self.locals.insert(arg.to_string(), value);
+            }
+        }
+    }
+
+    /// What a thread is waiting for when it gives up the rest of its turn.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Wait {
+        /// A thread to finish
+        Join(StackHandle),
+        /// Room on a full channel
+        Send(StackHandle),
+        /// A value on an empty channel
+        Recv(StackHandle),
+        /// The next value of a generator
+        Next(StackHandle)
+    }
+
+    /// A green thread waiting for its turn. The program's first thread has no
+    /// `stack`; the others are identified by the stack they were started on.
+    #[derive(Debug, Clone)]
+    pub struct Thread {
+        pub frames: Vec<Frame>,
+        pub stack: Option<StackHandle>,
+        pub current_stack: StackHandle,
+        pub waiting: Option<Wait>
+    }
+
+    /// A generator procedure's thread. It only runs while an 'each' loop is
+    /// waiting for its next value, and is parked in `thread` in between.
+    #[derive(Debug, Clone)]
+    pub struct Generator {
+        pub thread: Option<Thread>,
+        pub value: Option<DataTypes>,
+        pub finished: bool
+    }
+
+    /// Runs green threads in turn, each for `THREAD_SLICE` instructions or
+    /// until it has to wait, so that every run is the same.
+    #[derive(Debug, Clone)]
+    pub struct Scheduler {
+        pub threads: VecDeque<Thread>,
+        pub generators: HashMap<StackHandle, Generator>,
+        pub running: Option<StackHandle>,
+        pub waiting: Option<Wait>,
+        pub parking: bool,
+        pub slice: usize,
+        pub started: usize
+    }
+
+    impl Scheduler {
+        pub fn new() -> Self {
+            Scheduler {
+                threads: VecDeque::new(),
+                generators: HashMap::new(),
+                running: None,
+                waiting: None,
+                parking: false,
+                slice: THREAD_SLICE,
+                started: 0
+            }
+        }
+
+        /// Whether a thread started on `stack` has yet to finish.
+        pub fn is_alive(&self, stack: StackHandle) -> bool {
+            self.running == Some(stack) || self.threads.iter().any(|thread| thread.stack == Some(stack))
+        }
+    }
+
     pub struct Program<'a> {
         pub instructions: &'a mut Vec<Option<Instruction>>,
-        pub stack: &'a mut Vec<DataTypes>,
-        pub current_stack: Option<*mut Vec<DataTypes>>,
+        pub stack: &'a mut Stack,
+        pub current_stack: Option<StackHandle>,
         pub data_stack: &'a mut HashMap<String, DataTypes>,
         pub proc_stack: &'a mut HashMap<String, ProcedureDefine>,
-        pub stack_stack: &'a mut HashMap<String, Vec<DataTypes>>,
+        pub stack_stack: &'a mut HashMap<String, StackHandle>,
+        pub stack_arena: StackArena,
         pub names: &'a mut HashMap<String, StorageTypes>,
         pub file: String,
-        pub index: usize
+        pub index: usize,
+        pub frames: Vec<Frame>,
+        pub max_depth: usize,
+        pub unwinding: bool,
+        /// Instructions left while trying to evaluate code at compile time.
+        /// Running out, or reaching an instruction that would fail, sets
+        /// `gave_up` and stops the program instead of reporting an error.
+        pub budget: Option<usize>,
+        pub gave_up: bool,
+        pub gc_stats: bool,
+        pub scheduler: Scheduler
     }
 
     impl<'a> Program<'a> {
         fn evaluate_instruction(&mut self, instruction: &Instruction) {
             match &instruction.Instruction {
                 Instructions::PUSH(val) => {
-                    self.stack.push(DataTypes::INT(val.clone()));
+                    self.push(DataTypes::INT(val.clone()), instruction);
                 },
                 Instructions::PRINT => {
-                    if let Some(v) = self.stack.pop() {
+                    if let Some(v) = self.stack.pop_back() {
                         match v {
                             DataTypes::INT(u) => println!("{:?}", u),
-                            DataTypes::STACKPOINTER(p) => println!("{:?}", p),
-                            _ => report_err("Cannot print non-numeric types", instruction.file_name.as_str(), instruction.line_num.clone())
+                            DataTypes::STACKPOINTER(_) => println!("{}", self.show(&v)),
+                            _ => self.error("Cannot print non-numeric types", instruction)
                         }
                     }
                 },
                 Instructions::PRINTASCII => {
-                    print!("{}", match self.stack.pop().unwrap_or_else(|| report_err("Cannot pop value from empty stack", instruction.file_name.as_str(), instruction.line_num.clone())) {
+                    print!("{}", match self.stack.pop_back().unwrap_or_else(|| self.error("Cannot pop value from empty stack", instruction)) {
                         DataTypes::INT(u) => u as char,
-                        _ => report_err("Cannot print non-numeric values as ASCII", instruction.file_name.as_str(), instruction.line_num.clone()),
+                        _ => self.error("Cannot print non-numeric values as ASCII", instruction),
                     });
                 }
                 Instructions::POP => {
-                    self.stack.pop();
+                    self.stack.pop_back();
                 },
                 Instructions::DUP => {
-                    match self.stack.pop().unwrap_or_else(|| report_err("No data on stack to duplicate", instruction.file_name.as_str(), instruction.line_num.clone())) {
+                    match self.stack.pop_back().unwrap_or_else(|| self.error("No data on stack to duplicate", instruction)) {
                         DataTypes::INT(u) => {
-                            self.stack.push(DataTypes::INT(u));
-                            self.stack.push(DataTypes::INT(u));
+                            self.push(DataTypes::INT(u), instruction);
+                            self.push(DataTypes::INT(u), instruction);
                         },
                         DataTypes::STACKPOINTER(p) => {
-                            self.stack.push(DataTypes::STACKPOINTER(p));
-                            self.stack.push(DataTypes::STACKPOINTER(p));
+                            self.push(DataTypes::STACKPOINTER(p), instruction);
+                            self.push(DataTypes::STACKPOINTER(p), instruction);
                         },
-                        _ => report_err("Cannot duplicate extraneous types", instruction.file_name.as_str(), instruction.line_num.clone())
+                        _ => self.error("Cannot duplicate extraneous types", instruction)
                     }
                 },
                 Instructions::SWAP => {
-                    let first_val = self.stack.pop().unwrap_or_else(|| report_err("Insufficient data on the stack", instruction.file_name.as_str(), instruction.line_num.clone()));
-                    let second_val = self.stack.pop().unwrap_or_else(|| report_err("Insufficient data on the stack", instruction.file_name.as_str(), instruction.line_num.clone()));
-                    self.stack.push(first_val);
-                    self.stack.push(second_val);
+                    let first_val = self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction));
+                    let second_val = self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction));
+                    self.push(first_val, instruction);
+                    self.push(second_val, instruction);
                 },
                 Instructions::ADD => {
-                    let first_val = match self.stack.pop().unwrap_or_else(|| report_err("Insufficient data on the stack", instruction.file_name.as_str(), instruction.line_num.clone())) {
+                    let first_val = match self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction)) {
                       DataTypes::INT(u) => u,
-                      _ => {report_err("Cannot perform arithmetic operations on non-numeric values", instruction.file_name.as_str(), instruction.line_num.clone()); }
+                      _ => {self.error("Cannot perform arithmetic operations on non-numeric values", instruction); }
                     };
-                    let second_val = match self.stack.pop().unwrap_or_else(|| report_err("Insufficient data on the stack", instruction.file_name.as_str(), instruction.line_num.clone())) {
+                    let second_val = match self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction)) {
                         DataTypes::INT(u) => u,
-                        _ => {report_err("Cannot perform arithmetic operations on non-numeric values", instruction.file_name.as_str(), instruction.line_num.clone()); }
+                        _ => {self.error("Cannot perform arithmetic operations on non-numeric values", instruction); }
                     };
-                    self.stack.push(DataTypes::INT(second_val + first_val));
+                    self.push(DataTypes::INT(second_val + first_val), instruction);
                 },
                 Instructions::SUB => {
-                    let first_val = match self.stack.pop().unwrap_or_else(|| report_err("Insufficient data on the stack", instruction.file_name.as_str(), instruction.line_num.clone())) {
+                    let first_val = match self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction)) {
                         DataTypes::INT(u) => u,
-                        _ => {report_err("Cannot perform arithmetic operations on non-numeric values", instruction.file_name.as_str(), instruction.line_num.clone()); }
+                        _ => {self.error("Cannot perform arithmetic operations on non-numeric values", instruction); }
                     };
-                    let second_val = match self.stack.pop().unwrap_or_else(|| report_err("Insufficient data on the stack", instruction.file_name.as_str(), instruction.line_num.clone())) {
+                    let second_val = match self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction)) {
                         DataTypes::INT(u) => u,
-                        _ => {report_err("Cannot perform arithmetic operations on non-numeric values", instruction.file_name.as_str(), instruction.line_num.clone()); }
+                        _ => {self.error("Cannot perform arithmetic operations on non-numeric values", instruction); }
                     };
-                    self.stack.push(DataTypes::INT(second_val - first_val));
+                    self.push(DataTypes::INT(second_val - first_val), instruction);
                 },
                 Instructions::MULT => {
-                    let first_val = match self.stack.pop().unwrap_or_else(|| report_err("Insufficient data on the stack", instruction.file_name.as_str(), instruction.line_num.clone())) {
+                    let first_val = match self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction)) {
                         DataTypes::INT(u) => u,
-                        _ => {report_err("Cannot perform arithmetic operations on non-numeric values", instruction.file_name.as_str(), instruction.line_num.clone()); }
+                        _ => {self.error("Cannot perform arithmetic operations on non-numeric values", instruction); }
                     };
-                    let second_val = match self.stack.pop().unwrap_or_else(|| report_err("Insufficient data on the stack", instruction.file_name.as_str(), instruction.line_num.clone())) {
+                    let second_val = match self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction)) {
                         DataTypes::INT(u) => u,
-                        _ => {report_err("Cannot perform arithmetic operations on non-numeric values", instruction.file_name.as_str(), instruction.line_num.clone()); }
+                        _ => {self.error("Cannot perform arithmetic operations on non-numeric values", instruction); }
                     };
-                    self.stack.push(DataTypes::INT(second_val * first_val));
+                    self.push(DataTypes::INT(second_val * first_val), instruction);
                 },
                 Instructions::DIV => {
-                    let first_val = match self.stack.pop().unwrap_or_else(|| report_err("Insufficient data on the stack", instruction.file_name.as_str(), instruction.line_num.clone())) {
+                    let first_val = match self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction)) {
                         DataTypes::INT(u) => u,
-                        _ => {report_err("Cannot perform arithmetic operations on non-numeric values", instruction.file_name.as_str(), instruction.line_num.clone()); }
+                        _ => {self.error("Cannot perform arithmetic operations on non-numeric values", instruction); }
                     };
-                    let second_val = match self.stack.pop().unwrap_or_else(|| report_err("Insufficient data on the stack", instruction.file_name.as_str(), instruction.line_num.clone())) {
+                    let second_val = match self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction)) {
                         DataTypes::INT(u) => u,
-                        _ => {report_err("Cannot perform arithmetic operations on non-numeric values", instruction.file_name.as_str(), instruction.line_num.clone()); }
+                        _ => {self.error("Cannot perform arithmetic operations on non-numeric values", instruction); }
                     };
-                    self.stack.push(DataTypes::INT(second_val / first_val));
+                    self.push(DataTypes::INT(second_val / first_val), instruction);
                 },
                 Instructions::EQ => {
-                    let first_val = match self.stack.pop().unwrap_or_else(|| report_err("Insufficient data on the stack", instruction.file_name.as_str(), instruction.line_num.clone())) {
+                    let first_val = match self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction)) {
                         DataTypes::INT(u) => u,
-                        _ => {report_err("Cannot perform arithmetic operations on non-numeric values", instruction.file_name.as_str(), instruction.line_num.clone()); }
+                        _ => {self.error("Cannot perform arithmetic operations on non-numeric values", instruction); }
                     };
-                    let second_val = match self.stack.pop().unwrap_or_else(|| report_err("Insufficient data on the stack", instruction.file_name.as_str(), instruction.line_num.clone())) {
+                    let second_val = match self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction)) {
                         DataTypes::INT(u) => u,
-                        _ => {report_err("Cannot perform arithmetic operations on non-numeric values", instruction.file_name.as_str(), instruction.line_num.clone()); }
+                        _ => {self.error("Cannot perform arithmetic operations on non-numeric values", instruction); }
                     };
                     if second_val == first_val {
-                        self.stack.push(DataTypes::INT(1));
+                        self.push(DataTypes::INT(1), instruction);
                     } else {
-                        self.stack.push(DataTypes::INT(0));
+                        self.push(DataTypes::INT(0), instruction);
                     }
                 },
                 Instructions::LT => {
-                    let first_val = match self.stack.pop().unwrap_or_else(|| report_err("Insufficient data on the stack", instruction.file_name.as_str(), instruction.line_num.clone())) {
+                    let first_val = match self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction)) {
                         DataTypes::INT(u) => u,
-                        _ => {report_err("Cannot perform comparative operations on non-numeric values", instruction.file_name.as_str(), instruction.line_num.clone()); }
+                        _ => {self.error("Cannot perform comparative operations on non-numeric values", instruction); }
                     };
-                    let second_val = match self.stack.pop().unwrap_or_else(|| report_err("Insufficient data on the stack", instruction.file_name.as_str(), instruction.line_num.clone())) {
+                    let second_val = match self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction)) {
                         DataTypes::INT(u) => u,
-                        _ => {report_err("Cannot perform comparative operations on non-numeric values", instruction.file_name.as_str(), instruction.line_num.clone()); }
+                        _ => {self.error("Cannot perform comparative operations on non-numeric values", instruction); }
                     };
                     if second_val < first_val {
-                        self.stack.push(DataTypes::INT(1));
+                        self.push(DataTypes::INT(1), instruction);
                     } else {
-                        self.stack.push(DataTypes::INT(0));
+                        self.push(DataTypes::INT(0), instruction);
                     }
                 },
                 Instructions::GT => {
-                    let first_val = match self.stack.pop().unwrap_or_else(|| report_err("Insufficient data on the stack", instruction.file_name.as_str(), instruction.line_num.clone())) {
+                    let first_val = match self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction)) {
                         DataTypes::INT(u) => u,
-                        _ => {report_err("Cannot perform comparative operations on non-numeric values", instruction.file_name.as_str(), instruction.line_num.clone()); }
+                        _ => {self.error("Cannot perform comparative operations on non-numeric values", instruction); }
                     };
-                    let second_val = match self.stack.pop().unwrap_or_else(|| report_err("Insufficient data on the stack", instruction.file_name.as_str(), instruction.line_num.clone())) {
+                    let second_val = match self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction)) {
                         DataTypes::INT(u) => u,
-                        _ => {report_err("Cannot perform comparative operations on non-numeric values", instruction.file_name.as_str(), instruction.line_num.clone()); }
+                        _ => {self.error("Cannot perform comparative operations on non-numeric values", instruction); }
                     };
                     if second_val > first_val {
-                        self.stack.push(DataTypes::INT(1));
+                        self.push(DataTypes::INT(1), instruction);
                     } else {
-                        self.stack.push(DataTypes::INT(0));
+                        self.push(DataTypes::INT(0), instruction);
                     }
                 }
                 Instructions::If(nested_struct) => {
-                    match self.stack.pop().expect("No binary condition found") {
+                    match self.stack.pop_back().expect("No binary condition found") {
                         DataTypes::INT(1) => {
-                            for i in nested_struct.If.as_ref().unwrap() {
-                                if let Some(j) = i {
-                                    self.evaluate_instruction(&j);
-                                }
-                            }
+                            self.push_block(nested_struct.If.clone().unwrap_or_default(), BlockKind::Plain);
                         },
                         DataTypes::INT(0) => {
                             if let Some(instr) = nested_struct.Else.as_ref() {
                                 if instr.len() > 0 {
-                                    for i in instr {
-                                        if let Some(j) = i {
-                                            self.evaluate_instruction(&j);
-                                        }
-                                    }
+                                    self.push_block(instr.to_vec(), BlockKind::Plain);
                                 }
                             } else {
                                 return;
                             }
                         },
-                        _ => report_err("Binary boolean not found", instruction.file_name.as_str(), instruction.line_num.clone())
+                        _ => self.error("Binary boolean not found", instruction)
                     }
                 },
                 Instructions::While(nested_struct) => {
-                    for instr in &nested_struct.Cond {
-                        if let Some(i) = instr {
-                            self.evaluate_instruction(&i);
-                        }
-                    }
-                    while self.stack.pop().expect("No value found on stack") == DataTypes::INT(1) {
-                        for instr in &nested_struct.Contents {
-                            if let Some(i) = instr {
-                                self.evaluate_instruction(&i);
-                            }
-                        }
-                        for instr in &nested_struct.Cond {
-                            if let Some(i) = instr {
-                                self.evaluate_instruction(&i);
-                            }
-                        }
-                    }
+                    let nested_loop = Rc::new(Loop {
+                        instruction: instruction.clone(),
+                        cond: Rc::new(nested_struct.Cond.to_vec()),
+                        contents: Rc::new(nested_struct.Contents.to_vec())
+                    });
+                    self.current_frame().blocks.push(Block::new(Rc::clone(&nested_loop.cond), BlockKind::WhileCond(nested_loop)));
                 },
                 Instructions::VARDECLARE(nested_struct) => {
                     if let None = self.names.get(&nested_struct.name.to_string()) {
-                        let mut instrs = Vec::new();
-                        for i in self.instructions.clone() {
-                            instrs.push(i.clone().unwrap().Instruction);
-                        }
-
-                        self.names.insert(nested_struct.name.to_string(), StorageTypes::Variable);
-                        for instr in &nested_struct.instructions {
-                            self.evaluate_instruction(&instr.as_ref().unwrap());
-                        }
-                        self.data_stack.insert(
-                            nested_struct.name.to_string(),
-                            self.stack.pop().unwrap_or_else(|| report_err(format!("No data on stack to assign to variable {}", &nested_struct.name).as_str(), instruction.file_name.as_str(), instruction.line_num.clone()))
-                        );
-                    } else {
-                        if self.names.get(&nested_struct.name).unwrap() != &StorageTypes::Variable {
-                            report_err(format!("{} with name '{}' already exists", match &self.names.get(&nested_struct.name) {
-                                Some(StorageTypes::Procedure) => "Procedure",
-                                Some(StorageTypes::Variable) => "Variable",
-                                Some(StorageTypes::Stack) => "Stack",
-                                None => "Unknown",
-                            }, nested_struct.name).as_str(), instruction.file_name.as_str(), instruction.line_num.clone())
-                        } else {
-                            for instr in &nested_struct.instructions {
-                                self.evaluate_instruction(&instr.as_ref().unwrap());
-                            }
-                            self.data_stack.insert(
-                                nested_struct.name.to_string(),
-                                self.stack.pop().unwrap_or_else(|| report_err(format!("No data on stack to assign to variable {}", &nested_struct.name).as_str(), instruction.file_name.as_str(), instruction.line_num.clone()))
-                            );
+                        // Variables declared inside a procedure live in its frame instead
+                        if self.frames.len() == 1 {
+                            self.names.insert(nested_struct.name.to_string(), StorageTypes::Variable);
                         }
+                    } else if self.names.get(&nested_struct.name).unwrap() != &StorageTypes::Variable {
+                        self.error(format!("{} with name '{}' already exists", match &self.names.get(&nested_struct.name) {
+                            Some(StorageTypes::Procedure) => "Procedure",
+                            Some(StorageTypes::Variable) => "Variable",
+                            Some(StorageTypes::Stack) => "Stack",
+                            None => "Unknown",
+                        }, nested_struct.name).as_str(), instruction)
                     }
+                    self.push_block(nested_struct.instructions.to_vec(), BlockKind::Assign(instruction.clone()));
                 },
                 Instructions::DROP(name) => {
-                    if let Some(StorageTypes::Variable) = self.names.get(&name.to_string()) {
+                    if self.frames.len() > 1 && self.current_frame().locals.contains_key(name) {
+                        self.current_frame().locals.remove(name);
+                    } else if let Some(StorageTypes::Variable) = self.names.get(&name.to_string()) {
                         self.data_stack.remove(&name.to_string());
                     } else {
-                        report_err(format!("Variable {} does not exist", name).as_str(), instruction.file_name.as_str(), instruction.line_num.clone());
+                        self.error(format!("Variable {} does not exist", name).as_str(), instruction);
                     }
                 },
                 Instructions::IDENTIFIER(data_name) => {
-                    if let Some(data) = self.data_stack.get(data_name.as_str()) {
-                        self.stack.push(data.clone());
-                    } else if let Some(data) = self.proc_stack.clone().get(data_name) {
-                        for i in data.args.iter() {
-                            self.data_stack.insert(i.to_string(), self.stack.pop().unwrap_or_else(|| report_err("No value on stack to assign to parameter", instruction.file_name.as_str(), instruction.line_num.clone())));
-                        }
-
-                        for instr in &data.instructions.to_vec() {   
-                            self.evaluate_instruction(&instr);
-                        }
-
-
-
-                        for i in data.args.iter() {
-                            self.data_stack.remove(i);
+                    if let Some(data) = self.variable(data_name) {
+                        self.push(data, instruction);
+                    } else if let Some(data) = self.proc_stack.get(data_name).cloned() {
+                        self.call_procedure(data, instruction);
+                    }
+                },
+                Instructions::TAILPOSITION(data_name) => {
+                    if let Some(data) = self.variable(data_name) {
+                        self.push(data, instruction);
+                    } else if let Some(data) = self.proc_stack.get(data_name).cloned() {
+                        // The replacing frame keeps this one's stack, so a pure procedure
+                        // can only replace another pure procedure. Local stacks have to
+                        // stay open until the call returns
+                        let frame = self.frames.last().unwrap();
+                        if self.frames.len() > 1 && frame.defers.is_empty() && frame.local_stacks.is_empty() && frame.stack.is_none() == data.pure && !data.generator {
+                            let values = self.pop_arguments(&data, instruction);
+                            let frame = self.current_frame();
+                            frame.tail_call = Some((data, values));
+                            frame.blocks.clear();
+                        } else {
+                            self.call_procedure(data, instruction);
                         }
                     }
                 },
-                Instructions::SPAWN(name) => {
-                    if RESERVED_KEYWORDS.contains(&name.as_str()) { report_err(format!("ERROR: Cannot assign variable with name of assigned keyword ({})", name).as_str(), instruction.file_name.as_str(), instruction.line_num.clone()); }
+                Instructions::EACH(nested_struct) => {
+                    let nested_loop = Rc::new(EachLoop {
+                        instruction: instruction.clone(),
+                        contents: Rc::new(nested_struct.contents.to_vec()),
+                        reverse: nested_struct.reverse
+                    });
+                    self.push_block(nested_struct.source.to_vec(), BlockKind::EachSource(nested_loop));
+                },
+                Instructions::SPAWN(name, limits) => {
+                    if RESERVED_KEYWORDS.contains(&name.as_str()) { self.error(format!("ERROR: Cannot assign variable with name of assigned keyword ({})", name).as_str(), instruction); }
                     if let None = self.names.get(name) {
                         self.names.insert(name.to_string(), StorageTypes::Stack);
-                        self.stack_stack.insert(
-                            name.to_string(),
-                            Vec::new()
-                        );
-                        self.stack.push(DataTypes::STACKPOINTER(self.stack_stack.get_mut(name).unwrap() as *mut Vec<DataTypes>))
+                        let handle = self.stack_arena.insert(Stack::new(), Some(name.to_string()));
+                        self.stack_arena.set_limits(handle, limits.clone());
+                        self.stack_stack.insert(name.to_string(), handle);
+                        self.push(DataTypes::STACKPOINTER(handle), instruction)
                     } else {
-                        report_err(format!("{} with name '{}' already exists", match &self.names.get(name) {
+                        self.error(format!("{} with name '{}' already exists", match &self.names.get(name) {
                             Some(StorageTypes::Procedure) => "Procedure",
                             Some(StorageTypes::Variable) => "Variable",
                             Some(StorageTypes::Stack) => "Stack",
                             None => "Unknown",
-                        }, name).as_str(), instruction.file_name.as_str(), instruction.line_num.clone());
+                        }, name).as_str(), instruction);
                     }
                 },
+                Instructions::SPAWNLOCAL(name, limits) => {
+                    if self.frames.len() == 1 {
+                        self.error("Cannot spawn a local stack outside of a procedure", instruction);
+                    }
+                    if self.current_frame().local_stacks.contains_key(name) {
+                        self.error(format!("Local stack with name '{}' already exists", name).as_str(), instruction);
+                    }
+                    let handle = self.stack_arena.insert(Stack::new(), Some(name.to_string()));
+                    self.stack_arena.set_limits(handle, limits.clone());
+                    self.current_frame().local_stacks.insert(name.to_string(), handle);
+                    self.push(DataTypes::STACKPOINTER(handle), instruction);
+                },
                 Instructions::SWITCH => {
-                    if let Some(value) = self.stack.pop() {
+                    if let Some(value) = self.stack.pop_back() {
                         match value {
-                            DataTypes::STACKPOINTER(p) => {
-                                unsafe {
-                                    self.stack = &mut *p as &mut Vec<DataTypes>;
-                                    self.current_stack = Some(p);
-                                }
-                            },
-                            _ => report_err("Cannot switch to pointer with non-stack type", instruction.file_name.as_str(), instruction.line_num.clone()),
+                            DataTypes::STACKPOINTER(handle) => self.switch_stack(handle, instruction),
+                            _ => self.error("Cannot switch to pointer with non-stack type", instruction),
                         }
                     }
                 },
                 Instructions::CLOSE => {
-                    let name: Option<String> = None;
-                    let top = self.stack.pop();
-                    for k in self.stack_stack.clone().keys() {
-                        let r = self.stack_stack.get_mut(k).unwrap();
-                        let p1 = r as *mut Vec<DataTypes>;
-                        let mut p2 = None;
-                        if let Some(v) = top.clone() {
-                            p2 = match v {
-                                DataTypes::STACKPOINTER(p) => Some(p),
-                                _ => report_err("Cannot close non-pointer type", instruction.file_name.as_str(), instruction.line_num.clone()),
-                            };
-                        }
+                    if let Some(v) = self.stack.pop_back() {
+                        let handle = match v {
+                            DataTypes::STACKPOINTER(handle) => handle,
+                            _ => self.error("Cannot close non-pointer type", instruction),
+                        };
 
-                        if let Some(p) = p2 {
-                            if p1 == p {
-                                self.stack_stack.remove(k);
+                        if self.current_stack == Some(handle) {
+                            self.error("Cannot close the stack that is currently switched to", instruction);
+                        }
+                        let parked = self.scheduler.generators.values().filter_map(|generator| generator.thread.as_ref());
+                        if self.scheduler.threads.iter().chain(parked).any(|thread| thread.current_stack == handle) {
+                            self.error("Cannot close a stack another thread is switched to", instruction);
+                        }
+                        let name = self.stack_arena.name(handle).cloned();
+                        if self.stack_arena.remove(handle).is_none() {
+                            self.error("Cannot close a stack that has already been closed", instruction);
+                        }
+                        // Frees the name for another 'spawn'
+                        if let Some(name) = name {
+                            // A local stack can be closed from a callee or another thread
+                            let threads = self.scheduler.threads.iter_mut().flat_map(|thread| thread.frames.iter_mut());
+                            let parked = self.scheduler.generators.values_mut().filter_map(|generator| generator.thread.as_mut()).flat_map(|thread| thread.frames.iter_mut());
+                            let owner = self.frames.iter_mut().chain(threads).chain(parked).find(|frame| frame.local_stacks.get(&name) == Some(&handle));
+                            if let Some(frame) = owner {
+                                frame.local_stacks.remove(&name);
+                            } else if self.stack_stack.get(&name) == Some(&handle) {
+                                self.stack_stack.remove(&name);
+                                self.names.remove(&name);
                             }
                         }
                     }
-
-                    if let Some(hash_name) = name {
-                        self.stack_stack.remove(hash_name.as_str());
-                    }
                 },
                 Instructions::STACK(name) => {
-                  self.stack.push(DataTypes::STACKPOINTER(self.stack_stack.get_mut(name.as_str()).unwrap_or_else(|| report_err(format!("Cannot locate function with name {}", name).as_str(), instruction.file_name.as_str(), instruction.line_num.clone())) as *mut Vec<DataTypes>));
+                  let local = self.current_frame().local_stacks.get(name.as_str()).cloned();
+                  let handle = match local.or_else(|| self.stack_stack.get(name.as_str()).cloned()) {
+                      Some(handle) => handle,
+                      None => self.error(format!("Cannot locate function with name {}", name).as_str(), instruction)
+                  };
+                  self.push(DataTypes::STACKPOINTER(handle), instruction);
                 },
                 Instructions::THIS => {
-                  self.stack.push(DataTypes::STACKPOINTER(self.current_stack.unwrap()));
+                  self.push(DataTypes::STACKPOINTER(self.current_stack.unwrap()), instruction);
                 },
                 Instructions::STACKS => {
+                    let mut stacks: Vec<(String, StackHandle)> = self.stack_stack.iter().map(|(name, handle)| (name.to_string(), *handle)).collect();
+                    stacks.extend(self.frames.last().unwrap().local_stacks.iter().map(|(name, handle)| (format!("{} (local)", name), *handle)));
+                    stacks.sort_by(|a, b| a.0.cmp(&b.0));
+
                     println!("Stacks: ");
-                    for k in self.stack_stack.keys() {println!("  {}", k)};
+                    for (name, handle) in stacks {
+                        let stack = match self.contents(handle) {
+                            Some(stack) => stack,
+                            None => {
+                                println!("  {} (closed)", name);
+                                continue;
+                            }
+                        };
+                        let skipped = stack.len().saturating_sub(STACKS_PREVIEW);
+                        let mut values: Vec<String> = stack.iter().skip(skipped).map(|value| self.show(value)).collect();
+                        if skipped > 0 {
+                            values.insert(0, "...".to_string());
+                        }
+                        let marker = if self.current_stack == Some(handle) { " *" } else { "" };
+                        println!("{}", format!("  {}{} [{}] {}", name, marker, stack.len(), values.join(" ")).trim_end());
+                    }
+                },
+                Instructions::DUMP => {
+                    let values: Vec<String> = self.stack.iter().map(|value| self.show(value)).collect();
+                    println!("{}", format!("<{}> {}", self.stack.len(), values.join(" ")).trim_end());
                 },
                 Instructions::STACKSIZE => {
-                    self.stack.push(DataTypes::INT(self.stack.len() as u8));
+                    self.push(DataTypes::INT(self.stack.len() as u8), instruction);
                 },
                 Instructions::STACKREV => {
-                    self.stack.reverse();
+                    self.stack.make_contiguous().reverse();
+                },
+                Instructions::MOVE | Instructions::COPY => {
+                    let count = match self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction)) {
+                        DataTypes::INT(u) => u as usize,
+                        _ => self.error("Cannot use a pointer as the number of values to transfer", instruction)
+                    };
+                    let target = match self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction)) {
+                        DataTypes::STACKPOINTER(handle) => handle,
+                        _ => self.error("Cannot transfer values to non-pointer type", instruction)
+                    };
+                    if count > self.stack.len() {
+                        self.error(format!("Cannot transfer {} values from a stack holding {}", count, self.stack.len()).as_str(), instruction);
+                    }
+
+                    let values = if instruction.Instruction == Instructions::MOVE {
+                        self.stack.split_off(self.stack.len() - count)
+                    } else {
+                        self.stack.range(self.stack.len() - count..).cloned().collect()
+                    };
+                    self.check_limits(target, &values, count, instruction);
+                    self.stack_mut(target, instruction).extend(values);
+                },
+                Instructions::PEEK => {
+                    let index = self.pop_index(instruction);
+                    let target = self.pop_pointer(instruction);
+                    let value = self.element(target, index, instruction).clone();
+                    self.push(value, instruction);
+                },
+                Instructions::POKE => {
+                    let value = self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction));
+                    let index = self.pop_index(instruction);
+                    let target = self.pop_pointer(instruction);
+                    self.check_limits(target, [&value], 0, instruction);
+                    *self.element(target, index, instruction) = value;
+                },
+                Instructions::LEN => {
+                    let target = self.pop_pointer(instruction);
+                    let len = self.stack_mut(target, instruction).len();
+                    self.push(DataTypes::INT(len as u8), instruction);
                 },
-                Instructions::STRING(nested_instructions) => {
-                    for instruction in nested_instructions {
-                        if let Some(instr) = instruction {
-                            self.evaluate_instruction(instr);
+                Instructions::CLONE => {
+                    let target = self.pop_pointer(instruction);
+                    let contents = self.stack_mut(target, instruction).clone();
+                    self.push_anonymous(contents, instruction);
+                },
+                Instructions::CONCAT => {
+                    let second = self.pop_pointer(instruction);
+                    let first = self.pop_pointer(instruction);
+                    let mut contents = self.stack_mut(first, instruction).clone();
+                    contents.extend(self.stack_mut(second, instruction).iter().cloned());
+                    self.push_anonymous(contents, instruction);
+                },
+                Instructions::STACKEQ => {
+                    let second = self.pop_pointer(instruction);
+                    let first = self.pop_pointer(instruction);
+                    let equal = self.stack_mut(first, instruction).clone() == *self.stack_mut(second, instruction);
+                    self.push(DataTypes::INT(equal as u8), instruction);
+                },
+                Instructions::SORT => {
+                    let target = self.pop_pointer(instruction);
+                    let mut values = Vec::new();
+                    for value in self.stack_mut(target, instruction).iter() {
+                        match value {
+                            DataTypes::INT(u) => values.push(*u),
+                            _ => self.error("Cannot sort a stack holding pointers", instruction)
                         }
                     }
+                    values.sort();
+                    *self.stack_mut(target, instruction) = values.into_iter().map(DataTypes::INT).collect();
+                },
+                Instructions::SLICE => {
+                    let end = self.pop_index(instruction);
+                    let start = self.pop_index(instruction);
+                    let target = self.pop_pointer(instruction);
+                    let len = self.stack_mut(target, instruction).len();
+                    if start > end || end > len {
+                        self.error(format!("Slice {}..{} is out of range for a stack holding {} values", start, end, len).as_str(), instruction);
+                    }
+                    let contents = self.stack_mut(target, instruction).range(start..end).cloned().collect();
+                    self.push_anonymous(contents, instruction);
+                },
+                Instructions::PUSHFRONT => {
+                    let value = self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction));
+                    let target = self.pop_pointer(instruction);
+                    self.check_limits(target, [&value], 1, instruction);
+                    self.stack_mut(target, instruction).push_front(value);
+                },
+                Instructions::POPFRONT => {
+                    let target = self.pop_pointer(instruction);
+                    let value = self.stack_mut(target, instruction).pop_front().unwrap_or_else(|| self.error("Cannot pop value from empty stack", instruction));
+                    self.push(value, instruction);
+                },
+                Instructions::PEEKFRONT => {
+                    let target = self.pop_pointer(instruction);
+                    let value = self.stack_mut(target, instruction).front().cloned().unwrap_or_else(|| self.error("Cannot peek into empty stack", instruction));
+                    self.push(value, instruction);
+                },
+                Instructions::SAVESTACK => {
+                    let path = self.pop_string(instruction);
+                    let target = self.pop_pointer(instruction);
+                    if let Err(e) = self.save_stack(target, &path) {
+                        self.error(format!("Cannot save stack to '{}': {}", path, e).as_str(), instruction);
+                    }
+                },
+                Instructions::LOADSTACK => {
+                    let path = self.pop_string(instruction);
+                    let handle = self.load_stack(&path).unwrap_or_else(|e| self.error(format!("Cannot load stack from '{}': {}", path, e).as_str(), instruction));
+                    self.push(DataTypes::STACKPOINTER(handle), instruction);
+                },
+                Instructions::STRING(contents) => {
+                    // Literals get an anonymous stack, so the same literal can be used any number of times
+                    self.push_anonymous(contents.iter().map(|char| DataTypes::INT(*char)).collect(), instruction);
+                },
+                Instructions::LITERAL(nested_instructions) => {
+                    let return_stack = self.current_stack.unwrap();
+                    let handle = self.stack_arena.insert(Stack::new(), None);
+                    self.switch_stack(handle, instruction);
+                    self.push_block(nested_instructions.to_vec(), BlockKind::Literal(instruction.clone(), handle, return_stack));
                 },
                 Instructions::PROCEDURE(nested_struct) => {
                     if let None = self.names.get(&nested_struct.name.to_string()) {
-                        let rng = rand::thread_rng();
-                        let stack_name = rng.sample_iter(&Alphanumeric).take(10).map(char::from).collect::<String>();
-
-                        let mut new_vec = nested_struct.instructions.to_vec();
-                        new_vec.insert(0, Instruction::new(Instructions::SPAWN(stack_name.to_string()), 0, self.file.clone()));
-                        new_vec.insert(1, Instruction::new(Instructions::STACK(stack_name.to_string()), 0, self.file.clone()));
-                        new_vec.insert(2, Instruction::new(Instructions::SWITCH, 0, self.file.clone()));
-
-                        new_vec.push(Instruction::new(Instructions::STACK("main".to_string()), 0, self.file.clone()));
-                        new_vec.push(Instruction::new(Instructions::SWITCH, 0, self.file.clone()));
-                        new_vec.push(Instruction::new(Instructions::STACK(stack_name.to_string()), 0, self.file.clone()));
-                        new_vec.push(Instruction::new(Instructions::CLOSE, 0, self.file.clone()));
-
-                        let new_struct = ProcedureDefine {
-                            name: nested_struct.name.to_string(),
-                            args: nested_struct.args.to_vec(),
-                            instructions: new_vec,
-                            returns: nested_struct.returns.clone()
-                        };
-
                         self.names.insert(nested_struct.name.to_string(), StorageTypes::Procedure);
                         self.proc_stack.insert(
-                            new_struct.name.to_string(),
-                            new_struct.clone()
+                            nested_struct.name.to_string(),
+                            nested_struct.clone()
                         );
                     } else {
-                        report_err(format!("{} with name '{}' already exists", match &self.names.get(&nested_struct.name.to_string()) {
+                        self.error(format!("{} with name '{}' already exists", match &self.names.get(&nested_struct.name.to_string()) {
                             Some(StorageTypes::Procedure) => "Procedure",
                             Some(StorageTypes::Variable) => "Variable",
                             Some(StorageTypes::Stack) => "Stack",
                             None => "Unknown",
-                        }, nested_struct.name).as_str(), instruction.file_name.as_str(), instruction.line_num.clone());
+                        }, nested_struct.name).as_str(), instruction);
+                    }
+                },
+                Instructions::RETURN => {
+                    self.current_frame().blocks.clear();
+                },
+                Instructions::DEFER(nested_instructions) => {
+                    if self.frames.len() > 1 {
+                        self.current_frame().defers.push(nested_instructions.to_vec());
+                    } else {
+                        self.error("Cannot defer outside of a procedure call", instruction)
+                    }
+                },
+                Instructions::LET(nested_struct) => {
+                    let mut values = Vec::new();
+                    for name in nested_struct.names.iter().rev() {
+                        values.push(self.stack.pop_back().unwrap_or_else(|| self.error(format!("No data on stack to bind to '{}'", name).as_str(), instruction)));
+                    }
+                    values.reverse();
+
+                    let frame = self.current_frame();
+                    let scope = frame.bindings.len();
+                    frame.bindings.extend(values);
+                    self.push_block(nested_struct.instructions.to_vec(), BlockKind::Let(scope));
+                },
+                Instructions::BINDING(name, slot) => {
+                    match self.current_frame().bindings.get(*slot).cloned() {
+                        Some(value) => self.push(value, instruction),
+                        None => self.error(format!("Binding '{}' is out of scope", name).as_str(), instruction)
                     }
                 },
+                Instructions::CONST(_, _) | Instructions::DEFMACRO(_) => (),
+                Instructions::MACRO(nested_struct) => {
+                    self.push_block(nested_struct.instructions.to_vec(), BlockKind::Expansion(instruction.clone()));
+                },
                 Instructions::IMPORT(nested_instructions) => {
-                    for index in 0..nested_instructions.len() {
-                        self.evaluate_instruction(&nested_instructions[index].as_ref().unwrap());
+                    self.push_block(nested_instructions.to_vec(), BlockKind::Plain);
+                },
+                Instructions::GO(name) => {
+                    let procedure = self.proc_stack.get(name).cloned().unwrap_or_else(|| self.error(format!("Cannot start unknown procedure '{}'", name).as_str(), instruction));
+                    let values = self.pop_arguments(&procedure, instruction);
+
+                    // The thread's stack keeps whatever it leaves behind once it finishes
+                    self.scheduler.started += 1;
+                    let stack = self.stack_arena.insert(Stack::new(), Some(format!("{}#{}", name, self.scheduler.started)));
+                    let body = procedure.instructions.iter().cloned().map(Some).collect();
+                    let mut frame = Frame::new(Some(procedure.clone()), Some(instruction.clone()), Some(stack), None, body);
+                    frame.bind(&procedure.args, values);
+
+                    let thread = self.new_thread(frame, stack);
+                    self.scheduler.threads.push_back(thread);
+                    self.push(DataTypes::STACKPOINTER(stack), instruction);
+                },
+                Instructions::JOIN => {
+                    let target = self.pop_pointer(instruction);
+                    if self.scheduler.running == Some(target) {
+                        self.error("A thread cannot join itself", instruction);
+                    }
+                    if self.scheduler.is_alive(target) {
+                        self.stack.push_back(DataTypes::STACKPOINTER(target));
+                        self.rewind();
+                        self.wait(Wait::Join(target), instruction);
+                    }
+                },
+                Instructions::CHAN => {
+                    let capacity = self.pop_index(instruction);
+                    if capacity == 0 {
+                        self.error("Cannot make a channel with no room", instruction);
+                    }
+                    let handle = self.stack_arena.insert(Stack::new(), None);
+                    self.stack_arena.set_limits(handle, StackLimits {kind: None, capacity: Some(capacity)});
+                    self.push(DataTypes::STACKPOINTER(handle), instruction);
+                },
+                Instructions::SEND => {
+                    let value = self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction));
+                    let target = self.pop_pointer(instruction);
+                    if self.is_full(target, instruction) {
+                        self.stack.push_back(DataTypes::STACKPOINTER(target));
+                        self.stack.push_back(value);
+                        self.rewind();
+                        self.wait(Wait::Send(target), instruction);
+                        return;
+                    }
+                    self.check_limits(target, [&value], 1, instruction);
+                    self.stack_mut(target, instruction).push_back(value);
+                },
+                Instructions::RECV => {
+                    let target = self.pop_pointer(instruction);
+                    match self.stack_mut(target, instruction).pop_front() {
+                        Some(value) => self.push(value, instruction),
+                        None => {
+                            self.stack.push_back(DataTypes::STACKPOINTER(target));
+                            self.rewind();
+                            self.wait(Wait::Recv(target), instruction);
+                        }
+                    }
+                },
+                Instructions::YIELD => {
+                    let value = self.stack.pop_back().unwrap_or_else(|| self.error("No value on stack to yield", instruction));
+                    let generator = match self.scheduler.running.and_then(|stack| self.scheduler.generators.get_mut(&stack)) {
+                        Some(generator) => generator,
+                        None => self.error("Cannot yield outside of a generator", instruction)
                     };
+                    // Parked once this instruction is done, until the consumer wants another value
+                    generator.value = Some(value);
+                    self.scheduler.parking = true;
+                    self.scheduler.slice = 1;
                 },
                 Instructions::EXIT => {
-                    let code = self.stack.pop().unwrap_or_else(|| report_err("No exit code to exit with", instruction.file_name.as_str(), instruction.line_num));
+                    let code = self.stack.pop_back().unwrap_or_else(|| self.error("No exit code to exit with", instruction));
                     if let DataTypes::INT(exit_code) = code {
+                        self.report_gc_stats();
                         std::process::exit(exit_code as i32);
-                    } else {report_err("Cannot exit with status as pointer", instruction.file_name.as_str(), instruction.line_num);}
+                    } else {self.error("Cannot exit with status as pointer", instruction);}
                 }
             }
         }
 
-        pub fn simulate(&mut self) {
-            self.stack_stack.insert(
-                "main".to_string(),
-                Vec::new()
-            );
-
-            for instr in self.instructions.clone() {
-                if let Some(instruction) = instr {
-                    self.evaluate_instruction(&instruction);
+        fn pop_arguments(&mut self, procedure: &ProcedureDefine, instruction: &Instruction) -> Vec<DataTypes> {
+            let mut values = Vec::new();
+            for _ in procedure.args.iter() {
+                values.push(self.stack.pop_back().unwrap_or_else(|| self.error("No value on stack to assign to parameter", instruction)));
+            }
+            values
+        }
+
+        /// Looks a variable up in the current procedure's frame, falling back
+        /// to the globals.
+        fn variable(&self, name: &str) -> Option<DataTypes> {
+            let frame = self.frames.last().unwrap();
+            if self.frames.len() > 1 {
+                if let Some(data) = frame.locals.get(name) {
+                    return Some(data.clone());
                 }
             }
+            self.data_stack.get(name).cloned()
+        }
+
+        /// Makes `handle` the working stack. The contents of the stack being
+        /// left are put back into the arena.
+        fn switch_stack(&mut self, handle: StackHandle, instruction: &Instruction) {
+            if !self.stack_arena.contains(handle) {
+                self.error("Cannot switch to a stack that has been closed", instruction);
+            }
+
+            let current = self.current_stack.unwrap();
+            if handle != current {
+                *self.stack_arena.get_mut(current).unwrap() = std::mem::take(self.stack);
+                *self.stack = std::mem::take(self.stack_arena.get_mut(handle).unwrap());
+                self.current_stack = Some(handle);
+            }
+        }
+
+        /// Opens a stack with no name holding `contents` and pushes a pointer to it.
+        fn push_anonymous(&mut self, contents: Stack, instruction: &Instruction) {
+            let handle = self.stack_arena.insert(contents, None);
+            self.push(DataTypes::STACKPOINTER(handle), instruction);
+        }
+
+        /// Pushes onto the working stack, within whatever limits it was spawned with.
+        fn push(&mut self, value: DataTypes, instruction: &Instruction) {
+            self.check_limits(self.current_stack.unwrap(), [&value], 1, instruction);
+            self.stack.push_back(value);
+        }
+
+        /// Errors if `values` can't be put on the stack `handle` refers to, either
+        /// because of their type or because growing it by `added` values would
+        /// take it past its capacity.
+        fn check_limits<'v>(&mut self, handle: StackHandle, values: impl IntoIterator<Item = &'v DataTypes>, added: usize, instruction: &Instruction) {
+            let limits = match self.stack_arena.limits(handle) {
+                Some(limits) if *limits != StackLimits::default() => limits.clone(),
+                _ => return
+            };
+            let name = self.stack_arena.name(handle).cloned().unwrap_or_else(|| handle.to_string());
+
+            if let Some(kind) = limits.kind {
+                if let Some(value) = values.into_iter().find(|value| value.kind() != kind) {
+                    self.error(format!("Cannot push {} onto stack '{}', it only holds {}", value.kind(), name, kind).as_str(), instruction);
+                }
+            }
+            if let Some(capacity) = limits.capacity {
+                if self.stack_mut(handle, instruction).len() + added > capacity {
+                    self.error(format!("Cannot push onto stack '{}', it is full (capacity {})", name, capacity).as_str(), instruction);
+                }
+            }
+        }
+
+        fn pop_pointer(&mut self, instruction: &Instruction) -> StackHandle {
+            match self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction)) {
+                DataTypes::STACKPOINTER(handle) => handle,
+                _ => self.error("Expected a stack pointer", instruction)
+            }
+        }
+
+        /// Pops a pointer to a string and reads the characters it holds.
+        fn pop_string(&mut self, instruction: &Instruction) -> String {
+            let target = self.pop_pointer(instruction);
+            let mut res = String::new();
+            for value in self.stack_mut(target, instruction).iter() {
+                match value {
+                    DataTypes::INT(u) => res.push(*u as char),
+                    _ => self.error("Expected a string, found a stack holding pointers", instruction)
+                }
+            }
+            res
+        }
+
+        fn pop_index(&mut self, instruction: &Instruction) -> usize {
+            match self.stack.pop_back().unwrap_or_else(|| self.error("Insufficient data on the stack", instruction)) {
+                DataTypes::INT(u) => u as usize,
+                _ => self.error("Cannot use a pointer as an index", instruction)
+            }
+        }
+
+        /// The element `index` places up from the bottom of a stack.
+        fn element(&mut self, handle: StackHandle, index: usize, instruction: &Instruction) -> &mut DataTypes {
+            let len = self.stack_mut(handle, instruction).len();
+            if index >= len {
+                self.error(format!("Index {} is out of range for a stack holding {} values", index, len).as_str(), instruction);
+            }
+            &mut self.stack_mut(handle, instruction)[index]
+        }
+
+        /// Gives access to any open stack, including the one currently switched to.
+        fn stack_mut(&mut self, handle: StackHandle, instruction: &Instruction) -> &mut Stack {
+            if self.current_stack == Some(handle) {
+                return self.stack;
+            }
+            if !self.stack_arena.contains(handle) {
+                self.error("Cannot use a stack that has been closed", instruction);
+            }
+            self.stack_arena.get_mut(handle).unwrap()
+        }
+
+        /// Formats a value for printing. Pointers show the name of the stack
+        /// they refer to, or its handle if it has none.
+        fn show(&self, value: &DataTypes) -> String {
+            match value {
+                DataTypes::INT(u) => u.to_string(),
+                DataTypes::STACKPOINTER(p) => match self.stack_arena.name(*p) {
+                    Some(name) => format!("<stack {}>", name),
+                    None if self.stack_arena.contains(*p) => p.to_string(),
+                    None => format!("<closed stack {}:{}>", p.index, p.generation)
+                }
+            }
+        }
+
+        /// Read-only access to any open stack, including the one currently switched to.
+        fn contents(&self, handle: StackHandle) -> Option<&Stack> {
+            if self.current_stack == Some(handle) {
+                return Some(self.stack);
+            }
+            self.stack_arena.get(handle)
+        }
+
+        /// Saves the stack `handle` refers to to `path`, along with every stack
+        /// reachable from it through pointers.
+        pub fn save_stack(&self, handle: StackHandle, path: &str) -> io::Result<()> {
+            // Stacks are numbered in the order they are found, starting from 0
+            let mut ids: HashMap<StackHandle, usize> = HashMap::from([(handle, 0)]);
+            let mut pending = vec![handle];
+            let mut stacks = Vec::new();
+            while stacks.len() < pending.len() {
+                let stack = self.contents(pending[stacks.len()]).ok_or_else(|| Error::new(ErrorKind::NotFound, "it refers to a stack that has been closed"))?;
+                let mut saved = Stack::new();
+                for value in stack.iter() {
+                    match value {
+                        DataTypes::STACKPOINTER(p) => {
+                            let next = ids.len();
+                            let index = *ids.entry(*p).or_insert_with(|| {
+                                pending.push(*p);
+                                next
+                            });
+                            saved.push_back(DataTypes::STACKPOINTER(StackHandle {index, generation: 0}));
+                        },
+                        value => saved.push_back(value.clone())
+                    }
+                }
+                stacks.push((saved, self.stack_arena.limits(pending[stacks.len()]).cloned().unwrap_or_default()));
+            }
+            persist::write(path, &stacks)
+        }
+
+        /// Loads stacks saved with `save_stack` as new stacks without names,
+        /// returning a handle to the one that was saved.
+        pub fn load_stack(&mut self, path: &str) -> io::Result<StackHandle> {
+            let stacks = persist::read(path)?;
+            let handles: Vec<StackHandle> = stacks.iter().map(|_| self.stack_arena.insert(Stack::new(), None)).collect();
+            for (handle, (stack, limits)) in handles.iter().zip(stacks) {
+                *self.stack_arena.get_mut(*handle).unwrap() = stack.into_iter().map(|value| match value {
+                    DataTypes::STACKPOINTER(p) => DataTypes::STACKPOINTER(handles[p.index]),
+                    value => value
+                }).collect();
+                self.stack_arena.set_limits(*handle, limits);
+            }
+            Ok(handles[0])
+        }
+
+        fn current_frame(&mut self) -> &mut Frame {
+            self.frames.last_mut().unwrap()
+        }
+
+        fn push_block(&mut self, instructions: Vec<Option<Instruction>>, kind: BlockKind) {
+            self.current_frame().blocks.push(Block::new(Rc::new(instructions), kind));
+        }
+
+        /// Pushes a new frame for `procedure`, running its body on a freshly
+        /// spawned frame stack. A pure procedure runs on the caller's stack so
+        /// that its results are left behind.
+        fn call_procedure(&mut self, procedure: ProcedureDefine, instruction: &Instruction) {
+            if self.frames.len() > self.max_depth {
+                self.error(format!("Recursion limit exceeded (maximum depth is {})\n{}", self.max_depth, self.call_chain()).as_str(), instruction);
+            }
+
+            let values = self.pop_arguments(&procedure, instruction);
+            let body = procedure.instructions.iter().cloned().map(Some).collect();
+
+            if procedure.generator {
+                // Nothing runs until an 'each' loop asks for the first value
+                self.scheduler.started += 1;
+                let stack = self.stack_arena.insert(Stack::new(), Some(format!("{}#{}", procedure.name, self.scheduler.started)));
+                let mut frame = Frame::new(Some(procedure.clone()), Some(instruction.clone()), Some(stack), None, body);
+                frame.bind(&procedure.args, values);
+
+                let thread = self.new_thread(frame, stack);
+                self.scheduler.generators.insert(stack, Generator {thread: Some(thread), value: None, finished: false});
+                self.push(DataTypes::STACKPOINTER(stack), instruction);
+                return;
+            }
+
+            if procedure.pure {
+                let mut frame = Frame::new(Some(procedure.clone()), Some(instruction.clone()), None, None, body);
+                frame.bind(&procedure.args, values);
+                self.frames.push(frame);
+                return;
+            }
+
+            // Frame stacks have no name that could clash, only a label made of
+            // the procedure and call depth
+            let return_stack = self.current_stack;
+            let stack = self.stack_arena.insert(Stack::new(), Some(format!("{}@{}", procedure.name, self.frames.len())));
+            self.switch_stack(stack, instruction);
+
+            let mut frame = Frame::new(Some(procedure.clone()), Some(instruction.clone()), Some(stack), return_stack, body);
+            frame.bind(&procedure.args, values);
+            self.frames.push(frame);
+        }
+
+        /// Lists the active procedure calls, innermost first, folding runs of
+        /// the same call site into a single line.
+        fn call_chain(&self) -> String {
+            let mut calls: Vec<(String, String, u8, usize)> = Vec::new();
+            for frame in self.frames.iter().rev() {
+                if let (Some(procedure), Some(call_site)) = (&frame.procedure, &frame.call_site) {
+                    match calls.last_mut() {
+                        Some(last) if last.0 == procedure.name && last.1 == call_site.file_name && last.2 == call_site.line_num => last.3 += 1,
+                        _ => calls.push((procedure.name.to_string(), call_site.file_name.to_string(), call_site.line_num, 1))
+                    }
+                }
+            }
+
+            let mut res = Vec::new();
+            for (name, file, line_num, count) in calls {
+                if count > 1 {
+                    res.push(format!("    in '{}' called at {}:{} ({} times)", name, file, line_num, count));
+                } else {
+                    res.push(format!("    in '{}' called at {}:{}", name, file, line_num));
+                }
+            }
+            res.join("\n")
+        }
+
+        /// Handles a block that has run out of instructions.
+        fn finish_block(&mut self, block: Block) {
+            match block.kind {
+                BlockKind::Plain | BlockKind::Expansion(_) | BlockKind::Defer => (),
+                BlockKind::Let(scope) => {
+                    self.current_frame().bindings.truncate(scope);
+                },
+                BlockKind::WhileCond(nested_loop) => {
+                    if self.stack.pop_back().unwrap_or_else(|| self.error("No value found on stack", &nested_loop.instruction)) == DataTypes::INT(1) {
+                        self.current_frame().blocks.push(Block::new(Rc::clone(&nested_loop.contents), BlockKind::WhileBody(nested_loop)));
+                    }
+                },
+                BlockKind::WhileBody(nested_loop) => {
+                    self.current_frame().blocks.push(Block::new(Rc::clone(&nested_loop.cond), BlockKind::WhileCond(nested_loop)));
+                },
+                BlockKind::EachSource(nested_loop) => {
+                    let handle = match self.stack.pop_back().unwrap_or_else(|| self.error("No stack pointer found to iterate over", &nested_loop.instruction)) {
+                        DataTypes::STACKPOINTER(handle) => handle,
+                        _ => self.error("Cannot iterate over non-pointer type", &nested_loop.instruction)
+                    };
+                    let scope = self.current_frame().bindings.len();
+                    self.next_element(nested_loop, handle, 0, scope);
+                },
+                BlockKind::EachBody(nested_loop, handle, visited, scope) => {
+                    self.next_element(nested_loop, handle, visited, scope);
+                },
+                BlockKind::Literal(instruction, handle, return_stack) => {
+                    if !self.stack_arena.contains(return_stack) {
+                        self.error("Cannot finish stack literal, the stack it was started on has been closed", &instruction);
+                    }
+                    self.switch_stack(return_stack, &instruction);
+                    self.push(DataTypes::STACKPOINTER(handle), &instruction);
+                },
+                BlockKind::Assign(instruction) => {
+                    if let Instructions::VARDECLARE(nested_struct) = &instruction.Instruction {
+                        let value = self.stack.pop_back().unwrap_or_else(|| self.error(format!("No data on stack to assign to variable {}", &nested_struct.name).as_str(), &instruction));
+                        if self.frames.len() > 1 {
+                            self.current_frame().locals.insert(nested_struct.name.to_string(), value);
+                        } else {
+                            self.data_stack.insert(nested_struct.name.to_string(), value);
+                        }
+                    }
+                }
+            }
+        }
+
+        /// Binds the next element of an 'each' loop's stack and runs the body
+        /// again, or ends the loop once every element has been visited. The
+        /// stack is read as it is now, so the body may change it.
+        fn next_element(&mut self, nested_loop: Rc<EachLoop>, handle: StackHandle, visited: usize, scope: usize) {
+            self.current_frame().bindings.truncate(scope);
+
+            if let Some(generator) = self.scheduler.generators.get_mut(&handle) {
+                if let Some(value) = generator.value.take() {
+                    self.current_frame().bindings.push(value);
+                    self.current_frame().blocks.push(Block::new(Rc::clone(&nested_loop.contents), BlockKind::EachBody(nested_loop, handle, visited + 1, scope)));
+                } else if generator.finished {
+                    self.scheduler.generators.remove(&handle);
+                } else {
+                    // Lets the generator run up to its next 'yield', then tries again
+                    if let Some(thread) = generator.thread.take() {
+                        if !self.stack_arena.contains(thread.current_stack) {
+                            self.error("Cannot resume generator, the stack it is switched to has been closed", &nested_loop.instruction);
+                        }
+                        self.scheduler.threads.push_front(thread);
+                    }
+                    let instruction = nested_loop.instruction.clone();
+                    self.current_frame().blocks.push(Block::new(Rc::new(Vec::new()), BlockKind::EachBody(nested_loop, handle, visited, scope)));
+                    self.wait(Wait::Next(handle), &instruction);
+                }
+                return;
+            }
+
+            let stack = self.stack_mut(handle, &nested_loop.instruction);
+            if visited < stack.len() {
+                let index = if nested_loop.reverse { stack.len() - 1 - visited } else { visited };
+                let value = stack[index].clone();
+                self.current_frame().bindings.push(value);
+                self.current_frame().blocks.push(Block::new(Rc::clone(&nested_loop.contents), BlockKind::EachBody(nested_loop, handle, visited + 1, scope)));
+            }
+        }
+
+        /// Handles a frame whose body and deferred blocks have all run, either
+        /// handing it to a pending tail call or returning to the caller.
+        fn finish_frame(&mut self, mut frame: Frame) {
+            if frame.procedure.is_none() {
+                return;
+            }
+
+            let call_site = frame.call_site.clone().unwrap();
+            if let Some((next, values)) = frame.tail_call.take() {
+                if let Some(stack) = frame.stack {
+                    // The callee starts on an empty stack, like a normal call
+                    self.switch_stack(stack, &call_site);
+                    self.stack.clear();
+                }
+
+                let body = next.instructions.iter().cloned().map(Some).collect();
+                let mut next_frame = Frame::new(Some(next.clone()), frame.call_site, frame.stack, frame.return_stack, body);
+                next_frame.bind(&next.args, values);
+                self.frames.push(next_frame);
+                return;
+            }
+
+            if let (Some(stack), Some(return_stack)) = (frame.stack, frame.return_stack) {
+                if !self.stack_arena.contains(return_stack) {
+                    self.error("Cannot return to the calling stack, it has been closed", &call_site);
+                }
+                self.switch_stack(return_stack, &call_site);
+                self.close_local_stacks(&frame);
+                self.stack_arena.remove(stack);
+            } else if let Some(stack) = frame.stack {
+                // A green thread finishing. Its stack is left open for 'join'
+                if !self.stack_arena.contains(stack) {
+                    self.error("Cannot finish thread, the stack it was started on has been closed", &call_site);
+                }
+                self.switch_stack(stack, &call_site);
+                self.close_local_stacks(&frame);
+                if let Some(generator) = self.scheduler.generators.get_mut(&stack) {
+                    generator.finished = true;
+                }
+            }
+        }
+
+        /// Closes the stacks spawned with 'spawn local' during a call. The
+        /// caller has to have switched away from them first.
+        fn close_local_stacks(&mut self, frame: &Frame) {
+            for handle in frame.local_stacks.values() {
+                self.stack_arena.remove(*handle);
+            }
+        }
+
+        /// Executes the next instruction of the innermost frame, or winds down
+        /// whatever block or frame has run out.
+        fn step(&mut self) {
+            if self.stack_arena.needs_collection() {
+                self.collect_garbage();
+            }
+            if let Some(budget) = self.budget.as_mut() {
+                if *budget == 0 {
+                    self.give_up();
+                    return;
+                }
+                *budget -= 1;
+            }
+
+            let frame = self.current_frame();
+            if let Some(block) = frame.blocks.last_mut() {
+                if block.index < block.instructions.len() {
+                    let instructions = Rc::clone(&block.instructions);
+                    block.index += 1;
+                    if let Some(instruction) = &instructions[block.index - 1] {
+                        if self.budget.is_some() && !self.can_evaluate(instruction) {
+                            self.give_up();
+                            return;
+                        }
+                        self.evaluate_instruction(instruction);
+                    }
+                } else {
+                    let block = frame.blocks.pop().unwrap();
+                    if self.budget.is_some() && self.stack.is_empty() && matches!(block.kind, BlockKind::WhileCond(_) | BlockKind::Assign(_)) {
+                        self.give_up();
+                        return;
+                    }
+                    self.finish_block(block);
+                }
+            } else if let Some(deferred) = frame.defers.pop() {
+                // The body may have left bindings behind through 'return' or an
+                // error, and deferred blocks number theirs from zero
+                frame.bindings.clear();
+                frame.blocks.push(Block::new(Rc::new(deferred), BlockKind::Defer));
+
+                // An error can leave any stack switched to, which may be the full or
+                // typed stack that caused it, so unwinding goes back to the frame's own
+                if let (Some(stack), Some(call_site)) = (frame.stack, frame.call_site.clone()) {
+                    if self.unwinding && self.stack_arena.contains(stack) {
+                        self.switch_stack(stack, &call_site);
+                    }
+                }
+            } else {
+                let frame = self.frames.pop().unwrap();
+                self.finish_frame(frame);
+            }
+        }
+
+        /// Whether `instruction` can run without an error or overflow, checked
+        /// before each instruction of a compile-time evaluation. Only covers
+        /// what a pure procedure may contain.
+        fn can_evaluate(&self, instruction: &Instruction) -> bool {
+            let operands = match (self.stack.get(self.stack.len().wrapping_sub(2)), self.stack.back()) {
+                (Some(DataTypes::INT(second)), Some(DataTypes::INT(first))) => Some((*second, *first)),
+                _ => None
+            };
+            match &instruction.Instruction {
+                Instructions::DUP => !self.stack.is_empty(),
+                Instructions::SWAP => self.stack.len() >= 2,
+                Instructions::ADD => operands.and_then(|(second, first)| second.checked_add(first)).is_some(),
+                Instructions::SUB => operands.and_then(|(second, first)| second.checked_sub(first)).is_some(),
+                Instructions::MULT => operands.and_then(|(second, first)| second.checked_mul(first)).is_some(),
+                Instructions::DIV => operands.and_then(|(second, first)| second.checked_div(first)).is_some(),
+                Instructions::EQ | Instructions::LT | Instructions::GT => operands.is_some(),
+                Instructions::If(_) => matches!(self.stack.back(), Some(DataTypes::INT(0 | 1))),
+                Instructions::LET(nested_struct) => self.stack.len() >= nested_struct.names.len(),
+                Instructions::IDENTIFIER(name) | Instructions::TAILPOSITION(name) if self.variable(name).is_none() => {
+                    match self.proc_stack.get(name) {
+                        Some(procedure) => self.stack.len() >= procedure.args.len() && self.frames.len() <= self.max_depth,
+                        None => true
+                    }
+                },
+                _ => true
+            }
+        }
+
+        /// Stops a compile-time evaluation, leaving the code to run normally.
+        fn give_up(&mut self) {
+            self.gave_up = true;
+            self.frames.clear();
+        }
+
+        /// Closes every stack that is no longer reachable from a named stack,
+        /// the working stack, or a variable, argument or binding of any thread.
+        fn collect_garbage(&mut self) {
+            let mut roots: Vec<StackHandle> = self.stack_stack.values().cloned().collect();
+            roots.push(self.current_stack.unwrap());
+            roots.extend(self.stack.iter().chain(self.data_stack.values()).filter_map(Self::pointer));
+            Self::frame_roots(&self.frames, &mut roots);
+            for thread in self.scheduler.threads.iter() {
+                roots.extend(thread.stack.iter().chain([&thread.current_stack]));
+                Self::frame_roots(&thread.frames, &mut roots);
+            }
+
+            // A parked generator is only kept while its stack can be reached, and
+            // what it holds on to may reach further generators in turn
+            let mut marked = self.stack_arena.mark(roots.to_vec());
+            let mut kept: Vec<StackHandle> = Vec::new();
+            loop {
+                let reached: Vec<StackHandle> = self.scheduler.generators.keys().filter(|stack| !kept.contains(stack) && self.stack_arena.is_marked(&marked, **stack)).cloned().collect();
+                if reached.is_empty() {
+                    break;
+                }
+                for stack in reached {
+                    let generator = &self.scheduler.generators[&stack];
+                    roots.extend(generator.value.iter().filter_map(Self::pointer));
+                    if let Some(thread) = &generator.thread {
+                        roots.push(thread.current_stack);
+                        Self::frame_roots(&thread.frames, &mut roots);
+                    }
+                    kept.push(stack);
+                }
+                marked = self.stack_arena.mark(roots.to_vec());
+            }
+
+            self.scheduler.generators.retain(|stack, _| kept.contains(stack));
+            self.stack_arena.sweep(marked);
+        }
+
+        /// Adds the stacks a thread's frames refer to, directly or through a
+        /// variable, argument or binding.
+        fn frame_roots(frames: &Vec<Frame>, roots: &mut Vec<StackHandle>) {
+            for frame in frames.iter() {
+                roots.extend(frame.locals.values().chain(frame.bindings.iter()).filter_map(Self::pointer));
+                if let Some((_, args)) = &frame.tail_call {
+                    roots.extend(args.iter().filter_map(Self::pointer));
+                }
+                roots.extend(frame.stack.iter().chain(frame.return_stack.iter()).chain(frame.local_stacks.values()));
+                for block in frame.blocks.iter() {
+                    match &block.kind {
+                        BlockKind::EachBody(_, handle, _, _) => roots.push(*handle),
+                        BlockKind::Literal(_, handle, return_stack) => roots.extend([*handle, *return_stack]),
+                        _ => ()
+                    }
+                }
+            }
+        }
+
+        fn pointer(value: &DataTypes) -> Option<StackHandle> {
+            match value {
+                DataTypes::STACKPOINTER(p) => Some(*p),
+                _ => None
+            }
+        }
+
+        fn report_gc_stats(&self) {
+            if self.gc_stats {
+                let stats = &self.stack_arena.stats;
+                eprintln!("GC stats:");
+                eprintln!("  collections: {}", stats.collections);
+                eprintln!("  stacks reclaimed: {}", stats.reclaimed);
+                eprintln!("  stacks live: {}", self.stack_arena.len());
+                eprintln!("  peak live stacks: {}", stats.peak);
+            }
+        }
+
+        fn run(&mut self) {
+            loop {
+                if self.frames.is_empty() {
+                    match self.scheduler.threads.pop_front() {
+                        Some(next) => {
+                            self.stash();
+                            self.resume(next);
+                        },
+                        None => break
+                    }
+                }
+                self.step();
+
+                self.scheduler.slice -= 1;
+                if self.scheduler.slice == 0 && !self.frames.is_empty() {
+                    self.scheduler.slice = THREAD_SLICE;
+                    if self.scheduler.parking {
+                        self.scheduler.parking = false;
+                        let thread = self.suspend();
+                        let generator = self.scheduler.generators.get_mut(&thread.stack.unwrap()).unwrap();
+                        generator.thread = Some(thread);
+                    } else if let Some(next) = self.scheduler.threads.pop_front() {
+                        let thread = self.suspend();
+                        self.scheduler.threads.push_back(thread);
+                        self.resume(next);
+                    }
+                }
+            }
+        }
+
+        /// Makes a thread that starts by running `frame` on `stack`.
+        fn new_thread(&self, frame: Frame, stack: StackHandle) -> Thread {
+            // The bottom frame stands in for the top level, so that `frame` gets
+            // local variables like any other procedure call
+            Thread {
+                frames: vec![Frame::new(None, None, None, None, Vec::new()), frame],
+                stack: Some(stack),
+                current_stack: stack,
+                waiting: None
+            }
+        }
+
+        /// Puts the contents of the working stack back into the arena, if a
+        /// thread was still using it.
+        fn stash(&mut self) {
+            if let Some(current) = self.current_stack.take() {
+                *self.stack_arena.get_mut(current).unwrap() = std::mem::take(self.stack);
+            }
+        }
+
+        /// Takes the running thread off the interpreter.
+        fn suspend(&mut self) -> Thread {
+            let current = self.current_stack.unwrap();
+            self.stash();
+            Thread {
+                frames: std::mem::take(&mut self.frames),
+                stack: self.scheduler.running,
+                current_stack: current,
+                waiting: self.scheduler.waiting.take()
+            }
+        }
+
+        /// Ends the running thread's turn early, to try again once the others
+        /// have had theirs. Errors if every other thread is waiting too, since
+        /// then none of them can ever go on.
+        fn wait(&mut self, wait: Wait, instruction: &Instruction) {
+            if self.scheduler.threads.iter().all(|thread| thread.waiting.map_or(false, |wait| self.still_waiting(wait))) {
+                self.error("Deadlock, every thread is waiting on another", instruction);
+            }
+            self.scheduler.waiting = Some(wait);
+            self.scheduler.slice = 1;
+        }
+
+        /// Makes the instruction that just ran run again next, once whatever
+        /// it popped has been pushed back.
+        fn rewind(&mut self) {
+            self.current_frame().blocks.last_mut().unwrap().index -= 1;
+        }
+
+        fn still_waiting(&self, wait: Wait) -> bool {
+            match wait {
+                Wait::Join(stack) => self.scheduler.is_alive(stack),
+                Wait::Send(channel) => match (self.contents(channel), self.stack_arena.limits(channel)) {
+                    (Some(stack), Some(StackLimits {capacity: Some(capacity), ..})) => stack.len() >= *capacity,
+                    _ => false
+                },
+                Wait::Recv(channel) => self.contents(channel).map_or(false, |stack| stack.is_empty()),
+                Wait::Next(stack) => self.scheduler.generators.get(&stack).map_or(false, |generator| generator.value.is_none() && !generator.finished)
+            }
+        }
+
+        /// Whether a channel has no room for another value.
+        fn is_full(&mut self, channel: StackHandle, instruction: &Instruction) -> bool {
+            let len = self.stack_mut(channel, instruction).len();
+            match self.stack_arena.limits(channel) {
+                Some(StackLimits {capacity: Some(capacity), ..}) => len >= *capacity,
+                _ => false
+            }
+        }
+
+        fn resume(&mut self, thread: Thread) {
+            self.frames = thread.frames;
+            self.scheduler.running = thread.stack;
+            self.scheduler.slice = THREAD_SLICE;
+            self.current_stack = Some(thread.current_stack);
+            // 'close' refuses stacks that a waiting thread is switched to
+            *self.stack = self.stack_arena.get_mut(thread.current_stack).map(std::mem::take).unwrap_or_default();
+        }
+
+        /// Reports a runtime error, running every pending 'defer' block
+        /// (innermost procedure first) before the process exits.
+        fn error(&mut self, message: &str, instruction: &Instruction) -> ! {
+            eprintln!("{}:{} {}: {}", instruction.file_name, instruction.line_num, red("error"), message);
+            if let Some(frame) = self.frames.last() {
+                for block in frame.blocks.iter().rev() {
+                    if let BlockKind::Expansion(expansion) = &block.kind {
+                        if let Instructions::MACRO(nested_struct) = &expansion.Instruction {
+                            eprintln!("    in expansion of macro '{}' at {}:{}", nested_struct.name, expansion.file_name, expansion.line_num);
+                        }
+                    }
+                }
+            }
+
+            if !self.unwinding {
+                self.unwinding = true;
+                let threads = self.scheduler.threads.iter_mut().flat_map(|thread| thread.frames.iter_mut());
+                for frame in self.frames.iter_mut().chain(threads) {
+                    frame.blocks.clear();
+                    frame.tail_call = None;
+                }
+                self.run();
+            }
+            std::process::exit(1);
+        }
+
+        /// Runs `instructions` as an isolated program with `procedures` already
+        /// defined and returns what is left on its stack. Used to evaluate code
+        /// at compile time. With a `budget`, gives None instead of reporting an
+        /// error or running more than that many instructions.
+        pub fn evaluate(instructions: Vec<Option<Instruction>>, procedures: HashMap<String, ProcedureDefine>, file: String, budget: Option<usize>) -> Option<Vec<DataTypes>> {
+            let mut instructions = instructions;
+            let mut procedures = procedures;
+            let mut names = procedures.keys().map(|name| (name.to_string(), StorageTypes::Procedure)).collect();
+            let mut program = Program {
+                instructions: &mut instructions,
+                stack: &mut Stack::new(),
+                current_stack: None,
+                data_stack: &mut HashMap::new(),
+                proc_stack: &mut procedures,
+                stack_stack: &mut HashMap::new(),
+                stack_arena: StackArena::new(),
+                names: &mut names,
+                file,
+                index: 0,
+                frames: Vec::new(),
+                max_depth: DEFAULT_MAX_DEPTH,
+                unwinding: false,
+                budget,
+                gave_up: false,
+                gc_stats: false,
+                scheduler: Scheduler::new()
+            };
+
+            program.simulate();
+            if program.gave_up {
+                return None;
+            }
+            Some(program.stack.iter().cloned().collect())
+        }
+
+        pub fn simulate(&mut self) {
+            // The program starts on "main", whose contents are kept in `self.stack`
+            // while it is switched to
+            let main = self.stack_arena.insert(Stack::new(), Some("main".to_string()));
+            self.current_stack = Some(main);
+            self.stack_stack.insert("main".to_string(), main);
+            self.names.insert("main".to_string(), StorageTypes::Stack);
+
+            self.frames.push(Frame::new(None, None, None, None, self.instructions.to_vec()));
+            self.run();
+            self.report_gc_stats();
         }
     }
 }
\ No newline at end of file
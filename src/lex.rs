@@ -46,6 +46,46 @@ pub mod lex {
 
         fn is_file_path(c: char) -> bool {return !c.is_whitespace(); }
 
+        /// Reads the rest of an escape sequence in a string literal starting on
+        /// `line_num`, after its backslash.
+        fn get_escape(&mut self, line_num: u8) -> u8 {
+            match self.raw_data.next() {
+                Some('n') => b'\n',
+                Some('t') => b'\t',
+                Some('r') => b'\r',
+                Some('\\') => b'\\',
+                Some('"') => b'"',
+                Some('0') => 0,
+                Some('x') => {
+                    let digits: String = (0..2).filter_map(|_| self.raw_data.next_if(|c| c.is_ascii_hexdigit())).collect();
+                    if digits.len() != 2 {
+                        self.error("Expected two hex digits after '\\x'", self.line_num);
+                    }
+                    u8::from_str_radix(&digits, 16).unwrap()
+                },
+                Some('u') => {
+                    if self.raw_data.next() != Some('{') {
+                        self.error("Expected '{' after '\\u'", self.line_num);
+                    }
+                    let digits = self.get_next_char_while(String::new(), |c| c.is_ascii_hexdigit());
+                    if self.raw_data.next() != Some('}') || digits.is_empty() || digits.len() > 6 {
+                        self.error("Expected up to 6 hex digits and a '}' after '\\u{'", self.line_num);
+                    }
+                    // Strings are stored a byte per character
+                    match u8::try_from(u32::from_str_radix(&digits, 16).unwrap()) {
+                        Ok(byte) => byte,
+                        Err(_) => self.error(format!("'\\u{{{}}}' does not fit in a single byte", digits).as_str(), self.line_num)
+                    }
+                },
+                Some(c) => self.error(format!("Unknown escape sequence '\\{}'", c.escape_default()).as_str(), self.line_num),
+                None => self.error("Unterminated string literal", line_num)
+            }
+        }
+
+        fn error(&self, message: &str, line_num: u8) -> ! {
+            report_err(message, self.file.as_str(), line_num)
+        }
+
         fn get_numeric(&mut self, c: char) -> String {
             let mut res: String = c.to_string();
             res = self.get_next_char_while(res, |c| c.is_numeric());
@@ -81,43 +121,25 @@ pub mod lex {
                     } else if first_char == '\n' {
                         self.line_num += 1;
                     } else if first_char == '"' { // String literal
-                        let mut res: String = self.raw_data.next().expect("ERROR: Unexpected character \"").to_string();
-                        while self.raw_data.peek() != Some(&'"') {
-                            let char = self.raw_data.next();
-                            if let Some(c) = char {
-                                if c == '\\' {
-                                    if self.raw_data.next().unwrap() == 'n' {
-                                        res.push('\n');
+                        let line_num = self.line_num;
+                        let mut bytes = Vec::new();
+                        loop {
+                            match self.raw_data.next() {
+                                Some('"') => break,
+                                Some('\\') => bytes.push(self.get_escape(line_num)),
+                                Some(c) => {
+                                    if c == '\n' {
+                                        self.line_num += 1;
                                     }
-                                } else {res.push(c);}
-                            }
-                        }
-                        self.raw_data.next();
-
-                        let mut name: String = String::new();
-                        let mut words = res.split_whitespace();
-                        for _i in 0..3 {
-                            if let Some(unwrapped) = words.next() {
-                                for char in unwrapped.to_string().chars() {
-                                    if char.is_alphabetic() {
-                                        name.push(char);
+                                    match u8::try_from(c as u32) {
+                                        Ok(byte) => bytes.push(byte),
+                                        Err(_) => self.error(format!("'{}' does not fit in a single byte", c).as_str(), self.line_num)
                                     }
-                                }
-                                name.push('_');
+                                },
+                                None => self.error("Unterminated string literal", line_num)
                             }
                         }
-
-                        let mut instr: Vec<Option<Operation>> = Vec::new();
-                        instr.push(Some(Operation::new(OpCodes::SPAWN(name.clone()), self.line_num)));
-                        instr.push(Some(Operation::new(OpCodes::DUP, self.line_num)));
-                        instr.push(Some(Operation::new(OpCodes::SWITCH, self.line_num)));
-                        for char in res.chars() {
-                            instr.push(Some(Operation::new(OpCodes::PUSH(char as u8), self.line_num)))
-                        }
-                        instr.push(Some(Operation::new(OpCodes::STACK("main".to_string()), self.line_num)));
-                        instr.push(Some(Operation::new(OpCodes::SWITCH, self.line_num)));
-                        instr.push(Some(Operation::new(OpCodes::STACK(name.clone()), self.line_num)));
-                        return Some(Operation::new(OpCodes::STRING(instr), self.line_num));
+                        return Some(Operation::new(OpCodes::STRING(bytes), line_num));
                     } else {
                         let token: String = first_char.to_string();
                         let identifier = self.get_next_char_while(token, |c| Self::is_alphanumeric(c));
@@ -128,6 +150,9 @@ pub mod lex {
                             "if" => return Some(Operation::new(OpCodes::IF, self.line_num)),
                             "else" => return Some(Operation::new(OpCodes::ELSE, self.line_num)),
                             "while" => return Some(Operation::new(OpCodes::WHILE, self.line_num)),
+                            "each" => return Some(Operation::new(OpCodes::EACH, self.line_num)),
+                            "each_rev" => return Some(Operation::new(OpCodes::EACHREV, self.line_num)),
+                            "as" => return Some(Operation::new(OpCodes::AS, self.line_num)),
                             "end" => return Some(Operation::new(OpCodes::END, self.line_num)),
                             "do" => return Some(Operation::new(OpCodes::DO, self.line_num)),
                             "+" => return Some(Operation::new(OpCodes::ADD, self.line_num)),
@@ -153,6 +178,13 @@ pub mod lex {
                                 let token: String = self.raw_data.next().expect("ERROR: No character found").to_string();
                                 let name = self.get_next_char_while(token, |c| Self::is_alphanumeric(c));
 
+                                if name == "local" {
+                                    self.raw_data.next();
+                                    let token: String = self.raw_data.next().expect("ERROR: No character found").to_string();
+                                    let name = self.get_next_char_while(token, |c| Self::is_alphanumeric(c));
+
+                                    return Some(Operation::new(OpCodes::SPAWNLOCAL(name.to_string()), self.line_num));
+                                }
                                 return Some(Operation::new(OpCodes::SPAWN(name.to_string()), self.line_num));
                             },
                             "switch" => return Some(Operation::new(OpCodes::SWITCH, self.line_num)),
@@ -166,11 +198,31 @@ pub mod lex {
                             },
                             "this" => return Some(Operation::new(OpCodes::THIS, self.line_num)),
                             "stacks" => return Some(Operation::new(OpCodes::STACKS, self.line_num)),
+                            "dump" | ".s" => return Some(Operation::new(OpCodes::DUMP, self.line_num)),
                             "stack_size" => return Some(Operation::new(OpCodes::STACKSIZE, self.line_num)),
                             "stack_rev" => return Some(Operation::new(OpCodes::STACKREV, self.line_num)),
+                            "move" => return Some(Operation::new(OpCodes::MOVE, self.line_num)),
+                            "copy" => return Some(Operation::new(OpCodes::COPY, self.line_num)),
+                            "peek" => return Some(Operation::new(OpCodes::PEEK, self.line_num)),
+                            "poke" => return Some(Operation::new(OpCodes::POKE, self.line_num)),
+                            "len" => return Some(Operation::new(OpCodes::LEN, self.line_num)),
+                            "clone" => return Some(Operation::new(OpCodes::CLONE, self.line_num)),
+                            "concat" => return Some(Operation::new(OpCodes::CONCAT, self.line_num)),
+                            "stack_eq" => return Some(Operation::new(OpCodes::STACKEQ, self.line_num)),
+                            "sort" => return Some(Operation::new(OpCodes::SORT, self.line_num)),
+                            "slice" => return Some(Operation::new(OpCodes::SLICE, self.line_num)),
+                            "push_front" => return Some(Operation::new(OpCodes::PUSHFRONT, self.line_num)),
+                            "pop_front" => return Some(Operation::new(OpCodes::POPFRONT, self.line_num)),
+                            "peek_front" => return Some(Operation::new(OpCodes::PEEKFRONT, self.line_num)),
+                            "save_stack" => return Some(Operation::new(OpCodes::SAVESTACK, self.line_num)),
+                            "load_stack" => return Some(Operation::new(OpCodes::LOADSTACK, self.line_num)),
                             "procedure" => return Some(Operation::new(OpCodes::PROCEDURE, self.line_num)),
                             "in" => return Some(Operation::new(OpCodes::IN, self.line_num)),
                             "return" => return Some(Operation::new(OpCodes::RETURN, self.line_num)),
+                            "defer" => return Some(Operation::new(OpCodes::DEFER, self.line_num)),
+                            "let" => return Some(Operation::new(OpCodes::LET, self.line_num)),
+                            "const" => return Some(Operation::new(OpCodes::CONST, self.line_num)),
+                            "macro" => return Some(Operation::new(OpCodes::MACRO, self.line_num)),
                             "using" => {
                                 self.raw_data.next();
                                 let token: String = self.raw_data.next().expect("ERROR: No character found").to_string();
@@ -190,7 +242,17 @@ pub mod lex {
 
                                 return Some(Operation::new(OpCodes::IMPORT(res, value), self.line_num));
                             },
+                            "go" => return Some(Operation::new(OpCodes::GO, self.line_num)),
+                            "join" => return Some(Operation::new(OpCodes::JOIN, self.line_num)),
+                            "chan" => return Some(Operation::new(OpCodes::CHAN, self.line_num)),
+                            "send" => return Some(Operation::new(OpCodes::SEND, self.line_num)),
+                            "recv" => return Some(Operation::new(OpCodes::RECV, self.line_num)),
+                            "yield" => return Some(Operation::new(OpCodes::YIELD, self.line_num)),
                             "exit" => return Some(Operation::new(OpCodes::EXIT, self.line_num)),
+                            ":" => return Some(Operation::new(OpCodes::COLON, self.line_num)),
+                            "cap" => return Some(Operation::new(OpCodes::CAP, self.line_num)),
+                            "{" => return Some(Operation::new(OpCodes::LBRACE, self.line_num)),
+                            "}" => return Some(Operation::new(OpCodes::RBRACE, self.line_num)),
                             _ => return Some(Operation::new(OpCodes::IDENTIFIER(identifier.trim().to_string()), self.line_num))
                         }
                     }
@@ -5,12 +5,17 @@ mod globals;
 mod lex;
 mod parser;
 mod interpreter;
+mod arena;
+mod consteval;
+mod persist;
 
 use globals::globals::*;
 use globals::colorize::*;
 use lex::lex::Lexer;
 use parser::parser::Parser;
-use interpreter::program::Program;
+use interpreter::program::{Program, Scheduler, DEFAULT_MAX_DEPTH};
+use consteval::consteval::Folder;
+use arena::arena::StackArena;
 
 use std::collections::HashMap;
 use std::env;
@@ -18,18 +23,38 @@ use std::env;
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 3 {
+    if args.len() < 3 {
+        print_usage();
+    }
+
+    let mut max_depth = DEFAULT_MAX_DEPTH;
+    let mut gc_stats = false;
+    let mut paths = Vec::new();
+    for arg in args.iter().skip(2) {
+        if let Some(value) = arg.strip_prefix("--max-depth=") {
+            max_depth = value.parse().unwrap_or_else(|_| {
+                eprintln!("{}: invalid maximum depth \"{}\"", red("error"), value);
+                std::process::exit(1);
+            });
+        } else if arg == "--gc-stats" {
+            gc_stats = true;
+        } else {
+            paths.push(arg.to_string());
+        }
+    }
+
+    if paths.len() != 1 {
         print_usage();
     }
 
     let mut operations: Vec<Option<Operation>> = Vec::new();
 
-    if args[2].split(".").last().unwrap() != "mars" {
-        eprintln!("{}: unsupported file type \"{}\"", red("error"), args[2].split(".").last().unwrap());
+    if paths[0].split(".").last().unwrap() != "mars" {
+        eprintln!("{}: unsupported file type \"{}\"", red("error"), paths[0].split(".").last().unwrap());
         std::process::exit(1);
     } 
 
-    let file_name = args[2].split("/").last().unwrap();
+    let file_name = paths[0].split("/").last().unwrap();
 
     let lex = Lexer::from_file(file_name).unwrap();
 
@@ -45,24 +70,31 @@ fn main() {
         instructions.push(Some(instr));
     }
 
+    let mut instructions = Folder::new(file_name.to_string()).fold(instructions);
+
     if args[1] == "debug" {
         output_to_file(instructions.to_vec());
     } else if args[1] == "sim" {
         let mut program = Program {
             instructions: &mut instructions,
-            stack: &mut Vec::new(),
+            stack: &mut Stack::new(),
             current_stack: None,
             data_stack: &mut HashMap::new(),
             proc_stack: &mut HashMap::new(),
             stack_stack: &mut HashMap::new(),
+            stack_arena: StackArena::new(),
             names: &mut HashMap::new(),
             file: file_name.to_string(),
-            index: 0
+            index: 0,
+            frames: Vec::new(),
+            max_depth,
+            unwinding: false,
+            budget: None,
+            gave_up: false,
+            gc_stats,
+            scheduler: Scheduler::new()
         };
-    
-        program.current_stack = Some(program.stack as *mut Vec<DataTypes>);
-    
-    
+
         program.simulate();
     } else if args[1] == "com" {
         eprintln!("Compilation not yet supported");
@@ -73,6 +105,6 @@ fn main() {
 }
 
 fn print_usage() -> ! {
-    eprintln!("Usage: cargo run [debug][sim][com(WIP)] <filepath>");
+    eprintln!("Usage: cargo run [debug][sim][com(WIP)] [--max-depth=<n>] [--gc-stats] <filepath>");
     std::process::exit(1);
 }
\ No newline at end of file
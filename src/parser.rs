@@ -1,18 +1,53 @@
 pub mod parser {
     use crate::globals::globals::*;
+    use crate::globals::colorize::*;
+    use crate::interpreter::program::Program;
+    use std::collections::HashMap;
     use std::vec::IntoIter;
     use std::iter::Peekable;
 
+    pub const MAX_MACRO_DEPTH: usize = 64;
+
+    /// A macro's unparsed body, expanded afresh at every use.
+    #[derive(Debug, Clone)]
+    pub struct MacroDefine {
+        pub tokens: Vec<Option<Operation>>,
+        pub file_name: String,
+        pub line_num: u8
+    }
+
     pub struct Parser {
         operations: Peekable<IntoIter<Option<Operation>>>,
-        file: String
+        file: String,
+        in_procedure: bool,
+        in_defer: bool,
+        has_return: bool,
+        has_yield: bool,
+        has_defer: bool,
+        bindings: Vec<String>,
+        defer_floor: Option<usize>,
+        constants: HashMap<String, u8>,
+        pure_procedures: HashMap<String, ProcedureDefine>,
+        macros: HashMap<String, MacroDefine>,
+        expansions: Vec<(String, String, u8)>
     }
 
     impl Parser {
         pub fn new(data: Peekable<IntoIter<Option<Operation>>>, file: String) -> Self {
             Parser {
                 operations: data,
-                file
+                file,
+                in_procedure: false,
+                in_defer: false,
+                has_return: false,
+                has_yield: false,
+                has_defer: false,
+                bindings: Vec::new(),
+                defer_floor: None,
+                constants: HashMap::new(),
+                pure_procedures: HashMap::new(),
+                macros: HashMap::new(),
+                expansions: Vec::new()
             }
         }
 
@@ -117,9 +152,48 @@ pub mod parser {
                     )
 
                 },
-                OpCodes::END => report_err("'end' statement found without matching block", self.file.as_str(), op.line_num),
-                OpCodes::ELSE => report_err("'else' statement found without match 'if'", self.file.as_str(), op.line_num),
-                OpCodes::DO => report_err("'do' statement found without matching block", self.file.as_str(), op.line_num),
+                OpCodes::EACH | OpCodes::EACHREV => {
+                    let mut source: Vec<Option<Instruction>> = Vec::new();
+                    loop {
+                        let operation = self.operations.next().unwrap_or_else(|| self.error("'each' statement found without matching 'as'", op.line_num)).unwrap();
+                        if operation.OpCode == OpCodes::AS {
+                            break;
+                        }
+                        source.push(self.gen_instruction_from_op(operation));
+                    }
+                    let name = match self.operations.next().unwrap_or_else(|| self.error("'each' statement found without a name to bind", op.line_num)).unwrap().OpCode {
+                        OpCodes::IDENTIFIER(name) => name,
+                        _ => self.error("Expected identifier after 'as'", op.line_num)
+                    };
+                    if self.operations.next().unwrap_or_else(|| self.error("'each' statement found without matching 'do'", op.line_num)).unwrap().OpCode != OpCodes::DO {
+                        self.error("'each' statement found without matching 'do'", op.line_num);
+                    }
+
+                    let scope = self.bindings.len();
+                    self.bindings.push(name.to_string());
+
+                    let mut contents: Vec<Option<Instruction>> = Vec::new();
+                    while let Some(i) = self.operations.next() {
+                        if let Some(j) = i {
+                            if j.OpCode != OpCodes::END {
+                                contents.push(self.gen_instruction_from_op(j));
+                            } else {
+                                self.bindings.truncate(scope);
+                                return Some(Instruction::new(Instructions::EACH(Each {
+                                    name,
+                                    source,
+                                    contents,
+                                    reverse: op.OpCode == OpCodes::EACHREV
+                                }), op.line_num, self.file.clone()));
+                            }
+                        }
+                    }
+                    self.error("'each' statement found without matching 'end'", op.line_num);
+                },
+                OpCodes::AS => self.error("'as' statement found without matching 'each'", op.line_num),
+                OpCodes::END => self.error("'end' statement found without matching block", op.line_num),
+                OpCodes::ELSE => self.error("'else' statement found without match 'if'", op.line_num),
+                OpCodes::DO => self.error("'do' statement found without matching block", op.line_num),
                 OpCodes::VARDECLARE(name) => {
                     let mut instr: Vec<Option<Instruction>> = Vec::new();
 
@@ -129,7 +203,13 @@ pub mod parser {
                                 instr.push(self.gen_instruction_from_op(j));
                             } else {
                                 if RESERVED_KEYWORDS.contains(&name.as_str()) {
-                                    report_err(format!("Cannot assign variable with name of assigned keyword ({})", name).as_str(), self.file.as_str(), op.line_num);
+                                    self.error(format!("Cannot assign variable with name of assigned keyword ({})", name).as_str(), op.line_num);
+                                }
+                                if self.bindings.contains(&name) {
+                                    self.error(format!("Cannot assign to immutable binding '{}'", name).as_str(), op.line_num);
+                                }
+                                if self.constants.contains_key(&name) {
+                                    self.error(format!("Cannot assign variable with name of constant '{}'", name).as_str(), op.line_num);
                                 }
                                 return Some(Instruction::new(Instructions::VARDECLARE(VariableDefine {name: name.to_string(), instructions: instr}), op.line_num, self.file.clone()));
                             }
@@ -138,120 +218,488 @@ pub mod parser {
                     return Some(Instruction::new(Instructions::VARDECLARE(VariableDefine {name: name.to_string(), instructions: instr}), op.line_num, self.file.clone()));
                 },
                 OpCodes::DROP => {
-                    let name = match self.operations.next().unwrap_or_else(|| report_err("'drop' statement found without matching variable", self.file.as_str(), op.line_num)).unwrap().OpCode {
+                    let name = match self.operations.next().unwrap_or_else(|| self.error("'drop' statement found without matching variable", op.line_num)).unwrap().OpCode {
                         OpCodes::IDENTIFIER(name) => name,
-                        _ => report_err("Expected identifier after 'drop'", self.file.as_str(), op.line_num)
+                        _ => self.error("Expected identifier after 'drop'", op.line_num)
                     };
+                    if self.bindings.contains(&name) {
+                        self.error(format!("Cannot drop immutable binding '{}'", name).as_str(), op.line_num);
+                    }
 
                     return Some(Instruction::new(Instructions::DROP(name), op.line_num, self.file.clone()));
                 },
-                OpCodes::DEFINE => report_err("'def' statement found without matching variable declaration", self.file.as_str(), op.line_num),
-                OpCodes::IDENTIFIER(name) => Some(Instruction::new(Instructions::IDENTIFIER(name), op.line_num, self.file.clone())),
-                OpCodes::SPAWN(name) => Some(Instruction::new(Instructions::SPAWN(name), op.line_num, self.file.clone())),
+                OpCodes::DEFINE => self.error("'def' statement found without matching variable declaration", op.line_num),
+                OpCodes::IDENTIFIER(name) => {
+                    if let Some(slot) = self.bindings.iter().rposition(|binding| binding == &name) {
+                        let floor = self.defer_floor.unwrap_or(0);
+                        if slot < floor {
+                            self.error(format!("Binding '{}' cannot be used inside 'defer', it is out of scope when the block runs", name).as_str(), op.line_num);
+                        }
+                        return Some(Instruction::new(Instructions::BINDING(name, slot - floor), op.line_num, self.file.clone()));
+                    }
+                    if let Some(value) = self.constants.get(&name) {
+                        return Some(Instruction::new(Instructions::PUSH(*value), op.line_num, self.file.clone()));
+                    }
+                    if let Some(definition) = self.macros.get(&name).cloned() {
+                        return Some(self.expand_macro(name, definition, op.line_num));
+                    }
+                    Some(Instruction::new(Instructions::IDENTIFIER(name), op.line_num, self.file.clone()))
+                },
+                OpCodes::SPAWN(name) => {
+                    let limits = self.gen_stack_limits(op.line_num);
+                    Some(Instruction::new(Instructions::SPAWN(name, limits), op.line_num, self.file.clone()))
+                },
+                OpCodes::SPAWNLOCAL(name) => {
+                    if !self.in_procedure {
+                        self.error("'spawn local' statement found outside of 'procedure'", op.line_num);
+                    }
+                    let limits = self.gen_stack_limits(op.line_num);
+                    Some(Instruction::new(Instructions::SPAWNLOCAL(name, limits), op.line_num, self.file.clone()))
+                },
+                OpCodes::COLON => self.error("':' found outside of 'spawn' statement", op.line_num),
+                OpCodes::CAP => self.error("'cap' found outside of 'spawn' statement", op.line_num),
                 OpCodes::SWITCH => Some(Instruction::new(Instructions::SWITCH, op.line_num, self.file.clone())),
                 OpCodes::CLOSE => Some(Instruction::new(Instructions::CLOSE, op.line_num, self.file.clone())),
                 OpCodes::STACK(name) => Some(Instruction::new(Instructions::STACK(name), op.line_num, self.file.clone())),
                 OpCodes::THIS => Some(Instruction::new(Instructions::THIS, op.line_num, self.file.clone())),
                 OpCodes::STACKS => Some(Instruction::new(Instructions::STACKS, op.line_num, self.file.clone())),
+                OpCodes::DUMP => Some(Instruction::new(Instructions::DUMP, op.line_num, self.file.clone())),
                 OpCodes::STACKSIZE => Some(Instruction::new(Instructions::STACKSIZE, op.line_num, self.file.clone())),
-                OpCodes::STRING(contents) => {
-                    let mut instrs = Vec::new();
-                    for i in contents {
-                        if let Some(instr)= i {
-                            instrs.push(self.gen_instruction_from_op(instr));
+                OpCodes::STRING(contents) => Some(Instruction::new(Instructions::STRING(contents), op.line_num, self.file.clone())),
+                OpCodes::LBRACE => {
+                    let mut instrs: Vec<Option<Instruction>> = Vec::new();
+                    while let Some(i) = self.operations.next() {
+                        if let Some(j) = i {
+                            if j.OpCode != OpCodes::RBRACE {
+                                instrs.push(self.gen_instruction_from_op(j));
+                            } else {
+                                return Some(Instruction::new(Instructions::LITERAL(instrs), op.line_num, self.file.clone()));
+                            }
                         }
                     }
-                    Some(Instruction::new(Instructions::STRING(instrs), op.line_num, self.file.clone()))
+                    self.error("'{' found without matching '}'", op.line_num);
                 },
+                OpCodes::RBRACE => self.error("'}' found without matching '{'", op.line_num),
                 OpCodes::STACKREV => Some(Instruction::new(Instructions::STACKREV, op.line_num, self.file.clone())),
-                OpCodes::PROCEDURE => {
-                    let operation = self.operations.next().unwrap_or_else(|| report_err("'procedure' statement found without matching block", self.file.as_str(), op.line_num));
-
-                    if let OpCodes::IDENTIFIER(name) = operation.unwrap().OpCode {
-                        let mut args = Vec::new();
-                        let mut instructions = Vec::new();
-
-                        if self.operations.next().unwrap_or_else(|| report_err("'procedure' statement found with excessive name parameters", self.file.as_str(), op.line_num)).unwrap().OpCode == OpCodes::IN {
-                            loop {
-                                let operation = self.operations.peek().unwrap_or_else(|| report_err("'procedure' statement found with excessive name parameters", self.file.as_str(), op.line_num));
-                                if let OpCodes::IDENTIFIER(name) = &operation.as_ref().unwrap().OpCode {
-                                    args.push(name.to_string());
-                                    self.operations.next();
-                                } else {
-                                    break;
-                                }
-                            }
-                            if self.operations.next().unwrap_or_else(|| report_err("'procedure' statement found without body", self.file.as_str(), op.line_num)).unwrap().OpCode == OpCodes::DO {
-                                while let Some(i) = self.operations.next() {
-                                    if let Some(j) = i {
-                                        if j.OpCode != OpCodes::END {
-                                            instructions.push(self.gen_instruction_from_op(j).unwrap());
-                                        } else if j.OpCode == OpCodes::RETURN {
-                                            return Some(Instruction::new(Instructions::PROCEDURE(ProcedureDefine {
-                                                name: name.to_string(),
-                                                args: args,
-                                                instructions: instructions,
-                                                returns: true
-                                            }), op.line_num, self.file.clone()));
-                                        } else {
-                                            return Some(Instruction::new(Instructions::PROCEDURE(ProcedureDefine {
-                                                name: name.to_string(),
-                                                args: args,
-                                                instructions: instructions,
-                                                returns: false
-                                            }), op.line_num, self.file.clone()));
-                                        }
-                                    }
-                                }
+                OpCodes::MOVE => Some(Instruction::new(Instructions::MOVE, op.line_num, self.file.clone())),
+                OpCodes::COPY => Some(Instruction::new(Instructions::COPY, op.line_num, self.file.clone())),
+                OpCodes::PEEK => Some(Instruction::new(Instructions::PEEK, op.line_num, self.file.clone())),
+                OpCodes::POKE => Some(Instruction::new(Instructions::POKE, op.line_num, self.file.clone())),
+                OpCodes::LEN => Some(Instruction::new(Instructions::LEN, op.line_num, self.file.clone())),
+                OpCodes::CLONE => Some(Instruction::new(Instructions::CLONE, op.line_num, self.file.clone())),
+                OpCodes::CONCAT => Some(Instruction::new(Instructions::CONCAT, op.line_num, self.file.clone())),
+                OpCodes::STACKEQ => Some(Instruction::new(Instructions::STACKEQ, op.line_num, self.file.clone())),
+                OpCodes::SORT => Some(Instruction::new(Instructions::SORT, op.line_num, self.file.clone())),
+                OpCodes::SLICE => Some(Instruction::new(Instructions::SLICE, op.line_num, self.file.clone())),
+                OpCodes::PUSHFRONT => Some(Instruction::new(Instructions::PUSHFRONT, op.line_num, self.file.clone())),
+                OpCodes::POPFRONT => Some(Instruction::new(Instructions::POPFRONT, op.line_num, self.file.clone())),
+                OpCodes::PEEKFRONT => Some(Instruction::new(Instructions::PEEKFRONT, op.line_num, self.file.clone())),
+                OpCodes::SAVESTACK => Some(Instruction::new(Instructions::SAVESTACK, op.line_num, self.file.clone())),
+                OpCodes::LOADSTACK => Some(Instruction::new(Instructions::LOADSTACK, op.line_num, self.file.clone())),
+                OpCodes::PROCEDURE => Some(self.gen_procedure(op.line_num, false)),
+                OpCodes::RETURN => {
+                    if !self.in_procedure || self.in_defer {
+                        self.error("'return' statement found without matching 'procedure'", op.line_num);
+                    }
+                    self.has_return = true;
+                    Some(Instruction::new(Instructions::RETURN, op.line_num, self.file.clone()))
+                },
+                OpCodes::DEFER => {
+                    if !self.in_procedure {
+                        self.error("'defer' statement found outside of 'procedure'", op.line_num);
+                    }
+                    if self.in_defer {
+                        self.error("'defer' statement found inside another 'defer'", op.line_num);
+                    }
+
+                    self.in_defer = true;
+                    self.has_defer = true;
+                    self.defer_floor = Some(self.bindings.len());
+                    let mut instrs: Vec<Option<Instruction>> = Vec::new();
+                    while let Some(i) = self.operations.next() {
+                        if let Some(j) = i {
+                            if j.OpCode != OpCodes::END {
+                                instrs.push(self.gen_instruction_from_op(j));
+                            } else {
+                                self.in_defer = false;
+                                self.defer_floor = None;
+                                return Some(Instruction::new(Instructions::DEFER(instrs), op.line_num, self.file.clone()));
                             }
                         }
-                        else if self.operations.next().unwrap_or_else(|| report_err("'procedure' statement found without body", self.file.as_str(), op.line_num)).unwrap().OpCode == OpCodes::DO {
-                            while let Some(i) = self.operations.next() {
-                                if let Some(j) = i {
-                                    if j.OpCode != OpCodes::END {
-                                        instructions.push(self.gen_instruction_from_op(j).unwrap());
-                                    } else if j.OpCode == OpCodes::RETURN {
-                                        return Some(Instruction::new(Instructions::PROCEDURE(ProcedureDefine {
-                                            name: name.to_string(),
-                                            args: args,
-                                            instructions: instructions,
-                                            returns: true
-                                        }), op.line_num, self.file.clone()));
-                                    } else {
-                                        return Some(Instruction::new(Instructions::PROCEDURE(ProcedureDefine {
-                                            name: name.to_string(),
-                                            args: args,
-                                            instructions: instructions,
-                                            returns: false
-                                        }), op.line_num, self.file.clone()));
-                                    }
-                                }
+                    }
+                    self.error("'defer' statement found without matching 'end'", op.line_num);
+                },
+                OpCodes::IN => self.error("'in' statement found without matching 'procedure'", op.line_num),
+                OpCodes::LET => {
+                    let mut names = Vec::new();
+                    loop {
+                        let operation = self.operations.next().unwrap_or_else(|| self.error("'let' statement found without matching 'in'", op.line_num)).unwrap();
+                        match operation.OpCode {
+                            OpCodes::IDENTIFIER(name) => names.push(name),
+                            OpCodes::IN => break,
+                            _ => self.error("Expected identifier or 'in' after 'let'", op.line_num)
+                        }
+                    }
+                    if names.is_empty() {
+                        self.error("'let' statement found without any names to bind", op.line_num);
+                    }
+
+                    let scope = self.bindings.len();
+                    self.bindings.extend(names.iter().cloned());
+
+                    let mut instrs: Vec<Option<Instruction>> = Vec::new();
+                    while let Some(i) = self.operations.next() {
+                        if let Some(j) = i {
+                            if j.OpCode != OpCodes::END {
+                                instrs.push(self.gen_instruction_from_op(j));
+                            } else {
+                                self.bindings.truncate(scope);
+                                return Some(Instruction::new(Instructions::LET(LetBinding {
+                                    names,
+                                    instructions: instrs
+                                }), op.line_num, self.file.clone()));
                             }
                         }
-                        else {
-                            report_err("'procedure' statement found with unfinished definition", self.file.as_str(), op.line_num);
+                    }
+                    self.error("'let' statement found without matching 'end'", op.line_num);
+                },
+                OpCodes::CONST => {
+                    if let Some(Some(Operation {OpCode: OpCodes::PROCEDURE, ..})) = self.operations.peek() {
+                        self.operations.next();
+                        return Some(self.gen_procedure(op.line_num, true));
+                    }
+
+                    let name = match self.operations.next().unwrap_or_else(|| self.error("'const' statement found without a name", op.line_num)).unwrap().OpCode {
+                        OpCodes::IDENTIFIER(name) => name,
+                        _ => self.error("Expected identifier after 'const'", op.line_num)
+                    };
+                    if self.constants.contains_key(&name) {
+                        self.error(format!("Constant '{}' is already defined", name).as_str(), op.line_num);
+                    }
+
+                    // Runtime bindings have no value yet, so they are hidden from the expression
+                    let outer_bindings = std::mem::take(&mut self.bindings);
+                    let mut instrs: Vec<Option<Instruction>> = Vec::new();
+                    loop {
+                        let operation = self.operations.next().unwrap_or_else(|| self.error("'const' statement found without matching 'end'", op.line_num)).unwrap();
+                        if operation.OpCode == OpCodes::END {
+                            break;
                         }
+                        instrs.push(self.gen_instruction_from_op(operation));
                     }
-                    report_err("'procedure' statement found with unfinished definition", self.file.as_str(), op.line_num);
+                    self.bindings = outer_bindings;
+
+                    self.check_constant(&instrs, &mut Vec::new());
+                    let value = match Program::evaluate(instrs, self.pure_procedures.clone(), self.file.clone(), None).unwrap_or_default().as_slice() {
+                        [DataTypes::INT(value)] => *value,
+                        result => self.error(format!("Constant '{}' must leave exactly one integer on the stack (found {} values)", name, result.len()).as_str(), op.line_num)
+                    };
+
+                    self.constants.insert(name.to_string(), value);
+                    Some(Instruction::new(Instructions::CONST(name, value), op.line_num, self.file.clone()))
                 },
-                OpCodes::RETURN => report_err("'return' statement found without matching 'procedure'", self.file.as_str(), op.line_num),
-                OpCodes::IN => report_err("'in' statement found without matching 'procedure'", self.file.as_str(), op.line_num),
-                OpCodes::IMPORT(ops, file_path) => {
-                    let parse = Parser {
-                        operations: ops.into_iter().peekable(),
-                        file: file_path
+                OpCodes::MACRO => {
+                    let name = match self.operations.next().unwrap_or_else(|| self.error("'macro' statement found without a name", op.line_num)).unwrap().OpCode {
+                        OpCodes::IDENTIFIER(name) => name,
+                        _ => self.error("Expected identifier after 'macro'", op.line_num)
                     };
+                    if self.macros.contains_key(&name) {
+                        self.error(format!("Macro '{}' is already defined", name).as_str(), op.line_num);
+                    }
+                    if self.constants.contains_key(&name) {
+                        self.error(format!("Cannot define macro with name of constant '{}'", name).as_str(), op.line_num);
+                    }
+
+                    let mut tokens = Vec::new();
+                    let mut depth = 0;
+                    loop {
+                        let operation = self.operations.next().unwrap_or_else(|| self.error("'macro' statement found without matching 'end'", op.line_num)).unwrap();
+                        match operation.OpCode {
+                            OpCodes::IF | OpCodes::WHILE | OpCodes::EACH | OpCodes::EACHREV | OpCodes::PROCEDURE | OpCodes::DEFER | OpCodes::LET => depth += 1,
+                            // 'const procedure' is closed by the procedure's 'end' alone
+                            OpCodes::CONST => if !matches!(self.operations.peek(), Some(Some(Operation {OpCode: OpCodes::PROCEDURE, ..}))) {
+                                depth += 1;
+                            },
+                            OpCodes::MACRO => self.error("Cannot define a macro inside another macro", operation.line_num),
+                            OpCodes::END if depth == 0 => break,
+                            OpCodes::END => depth -= 1,
+                            _ => ()
+                        }
+                        tokens.push(Some(operation));
+                    }
+
+                    self.macros.insert(name.to_string(), MacroDefine {
+                        tokens,
+                        file_name: self.file.clone(),
+                        line_num: op.line_num
+                    });
+                    Some(Instruction::new(Instructions::DEFMACRO(name), op.line_num, self.file.clone()))
+                },
+                OpCodes::IMPORT(ops, file_path) => {
+                    let mut parse = Parser::new(ops.into_iter().peekable(), file_path);
+                    parse.constants = std::mem::take(&mut self.constants);
+                    parse.pure_procedures = std::mem::take(&mut self.pure_procedures);
+                    parse.macros = std::mem::take(&mut self.macros);
 
                     let mut instrs = Vec::new();
-                    for i in parse {
+                    while let Some(i) = parse.next() {
                         instrs.push(Some(i));
                     }
+                    self.constants = parse.constants;
+                    self.pure_procedures = parse.pure_procedures;
+                    self.macros = parse.macros;
 
                     Some(Instruction::new(Instructions::IMPORT(instrs), op.line_num, self.file.clone()))
                 },
+                OpCodes::GO => {
+                    match self.operations.next().flatten().map(|op| op.OpCode) {
+                        Some(OpCodes::IDENTIFIER(name)) => Some(Instruction::new(Instructions::GO(name), op.line_num, self.file.clone())),
+                        _ => self.error("'go' statement found without procedure name", op.line_num)
+                    }
+                },
+                OpCodes::JOIN => Some(Instruction::new(Instructions::JOIN, op.line_num, self.file.clone())),
+                OpCodes::CHAN => Some(Instruction::new(Instructions::CHAN, op.line_num, self.file.clone())),
+                OpCodes::SEND => Some(Instruction::new(Instructions::SEND, op.line_num, self.file.clone())),
+                OpCodes::RECV => Some(Instruction::new(Instructions::RECV, op.line_num, self.file.clone())),
+                OpCodes::YIELD => {
+                    if !self.in_procedure || self.in_defer {
+                        self.error("'yield' statement found without matching 'procedure'", op.line_num);
+                    }
+                    self.has_yield = true;
+                    Some(Instruction::new(Instructions::YIELD, op.line_num, self.file.clone()))
+                },
                 OpCodes::EXIT => Some(Instruction::new(Instructions::EXIT, op.line_num, self.file.clone()))
             }
         }
+
+        /// Rewrites the name a procedure body ends on into a TAILPOSITION,
+        /// following both branches of a trailing 'if'. Arguments are left as
+        /// they are, since they can never be calls.
+        fn mark_tail_call(instruction: Option<&mut Instruction>, args: &[String]) {
+            if let Some(instr) = instruction {
+                match &mut instr.Instruction {
+                    Instructions::IDENTIFIER(name) if !args.contains(name) => {
+                        instr.Instruction = Instructions::TAILPOSITION(name.to_string());
+                    },
+                    Instructions::If(nested_struct) => {
+                        for block in [&mut nested_struct.If, &mut nested_struct.Else] {
+                            if let Some(instrs) = block {
+                                Self::mark_tail_call(instrs.iter_mut().rev().find_map(|i| i.as_mut()), args);
+                            }
+                        }
+                    },
+                    Instructions::MACRO(nested_struct) => {
+                        Self::mark_tail_call(nested_struct.instructions.iter_mut().rev().find_map(|i| i.as_mut()), args);
+                    },
+                    _ => ()
+                }
+            }
+        }
+
+        /// Parses a macro's body in place of its use. The body keeps the
+        /// definition's file and lines, while the returned node is placed at
+        /// the use site.
+        fn expand_macro(&mut self, name: String, definition: MacroDefine, line_num: u8) -> Instruction {
+            if self.expansions.len() >= MAX_MACRO_DEPTH {
+                self.error(format!("Macro expansion depth limit of {} exceeded while expanding '{}'", MAX_MACRO_DEPTH, name).as_str(), line_num);
+            }
+
+            self.expansions.push((name.to_string(), self.file.clone(), line_num));
+            let use_file = std::mem::replace(&mut self.file, definition.file_name.to_string());
+            let use_operations = std::mem::replace(&mut self.operations, definition.tokens.into_iter().peekable());
+
+            let mut instrs = Vec::new();
+            while let Some(i) = self.operations.next() {
+                if let Some(j) = i {
+                    instrs.push(self.gen_instruction_from_op(j));
+                }
+            }
+
+            self.operations = use_operations;
+            self.file = use_file;
+            self.expansions.pop();
+
+            Instruction::new(Instructions::MACRO(Macro {
+                name,
+                instructions: instrs,
+                file_name: definition.file_name,
+                line_num: definition.line_num
+            }), line_num, self.file.clone())
+        }
+
+        fn error(&self, message: &str, line_num: u8) -> ! {
+            self.error_at(message, self.file.as_str(), line_num)
+        }
+
+        /// Reports a parse error, noting every macro expansion it happened in.
+        /// Repeated expansions from the same place are shown once with a count.
+        fn error_at(&self, message: &str, file: &str, line_num: u8) -> ! {
+            eprintln!("{}:{} {}: {}", file, line_num, red("error"), message);
+            let mut expansions: Vec<(&(String, String, u8), usize)> = Vec::new();
+            for expansion in self.expansions.iter().rev() {
+                match expansions.last_mut() {
+                    Some(last) if last.0 == expansion => last.1 += 1,
+                    _ => expansions.push((expansion, 1))
+                }
+            }
+            for ((name, use_file, use_line), count) in expansions {
+                if count > 1 {
+                    eprintln!("    in expansion of macro '{}' at {}:{} ({} times)", name, use_file, use_line, count);
+                } else {
+                    eprintln!("    in expansion of macro '{}' at {}:{}", name, use_file, use_line);
+                }
+            }
+            std::process::exit(1);
+        }
+
+        /// Reports the first instruction that can't be evaluated at parse time.
+        /// `names` holds the identifiers that resolve to values while it runs,
+        /// such as a pure procedure's arguments and locals.
+        fn check_constant(&self, instructions: &Vec<Option<Instruction>>, names: &mut Vec<String>) {
+            for instr in instructions.iter().flatten() {
+                match &instr.Instruction {
+                    Instructions::PUSH(_) | Instructions::POP | Instructions::DUP | Instructions::SWAP |
+                    Instructions::ADD | Instructions::SUB | Instructions::MULT | Instructions::DIV |
+                    Instructions::EQ | Instructions::LT | Instructions::GT | Instructions::BINDING(_, _) |
+                    Instructions::DROP(_) | Instructions::RETURN => (),
+                    Instructions::If(nested_struct) => {
+                        self.check_constant(nested_struct.If.as_ref().unwrap(), names);
+                        self.check_constant(nested_struct.Else.as_ref().unwrap(), names);
+                    },
+                    Instructions::While(nested_struct) => {
+                        self.check_constant(&nested_struct.Cond, names);
+                        self.check_constant(&nested_struct.Contents, names);
+                    },
+                    Instructions::VARDECLARE(nested_struct) => {
+                        self.check_constant(&nested_struct.instructions, names);
+                        names.push(nested_struct.name.to_string());
+                    },
+                    Instructions::LET(nested_struct) => self.check_constant(&nested_struct.instructions, names),
+                    Instructions::MACRO(nested_struct) => self.check_constant(&nested_struct.instructions, names),
+                    Instructions::IDENTIFIER(name) | Instructions::TAILPOSITION(name) => {
+                        if !names.contains(name) && !self.pure_procedures.contains_key(name) {
+                            self.error_at(format!("'{}' is not a constant or 'const procedure'", name).as_str(), instr.file_name.as_str(), instr.line_num);
+                        }
+                    },
+                    other => self.error_at(format!("Cannot use {} at compile time", other.to_string().lines().next().unwrap_or_default()).as_str(), instr.file_name.as_str(), instr.line_num)
+                }
+            }
+        }
+
+        /// Parses the optional ': <type>' and 'cap <n>' following the name in
+        /// a 'spawn' statement.
+        fn gen_stack_limits(&mut self, line_num: u8) -> StackLimits {
+            let mut limits = StackLimits::default();
+
+            if let Some(Some(Operation {OpCode: OpCodes::COLON, ..})) = self.operations.peek() {
+                self.operations.next();
+                limits.kind = match self.operations.next().flatten().map(|op| op.OpCode) {
+                    Some(OpCodes::IDENTIFIER(name)) if name == "int" => Some(DataKind::INT),
+                    Some(OpCodes::IDENTIFIER(name)) if name == "ptr" => Some(DataKind::STACKPOINTER),
+                    _ => self.error("Expected 'int' or 'ptr' after ':' in 'spawn' statement", line_num)
+                };
+            }
+
+            if let Some(Some(Operation {OpCode: OpCodes::CAP, ..})) = self.operations.peek() {
+                self.operations.next();
+                limits.capacity = match self.operations.next().flatten().map(|op| op.OpCode) {
+                    Some(OpCodes::PUSH(capacity)) => Some(capacity as usize),
+                    Some(OpCodes::IDENTIFIER(name)) if self.constants.contains_key(&name) => Some(self.constants[&name] as usize),
+                    _ => self.error("Expected a number or constant after 'cap' in 'spawn' statement", line_num)
+                };
+            }
+            limits
+        }
+
+        /// Parses a procedure definition following the 'procedure' keyword.
+        /// A pure procedure is checked to be evaluable at compile time.
+        fn gen_procedure(&mut self, line_num: u8, pure: bool) -> Instruction {
+            let operation = self.operations.next().unwrap_or_else(|| self.error("'procedure' statement found without matching block", line_num));
+
+            if let OpCodes::IDENTIFIER(name) = operation.unwrap().OpCode {
+                if self.constants.contains_key(&name) {
+                    self.error(format!("Cannot define procedure with name of constant '{}'", name).as_str(), line_num);
+                }
+                let mut args = Vec::new();
+
+                let mut next = self.operations.next().unwrap_or_else(|| self.error("'procedure' statement found without body", line_num)).unwrap();
+                if next.OpCode == OpCodes::IN {
+                    loop {
+                        let operation = match self.operations.peek() {
+                            Some(operation) => operation.clone(),
+                            None => self.error("'procedure' statement found with excessive name parameters", line_num)
+                        };
+                        if let OpCodes::IDENTIFIER(name) = &operation.unwrap().OpCode {
+                            args.push(name.to_string());
+                            self.operations.next();
+                        } else {
+                            break;
+                        }
+                    }
+                    next = self.operations.next().unwrap_or_else(|| self.error("'procedure' statement found without body", line_num)).unwrap();
+                }
+
+                if next.OpCode != OpCodes::DO {
+                    self.error("'procedure' statement found with unfinished definition", line_num);
+                }
+
+                let (instructions, returns, generator) = self.gen_procedure_body(line_num, &args);
+                let procedure = ProcedureDefine {
+                    name: name.to_string(),
+                    args,
+                    instructions,
+                    returns,
+                    pure,
+                    generator
+                };
+
+                if pure {
+                    let mut names = procedure.args.to_vec();
+                    names.push(procedure.name.to_string());
+                    self.check_constant(&procedure.instructions.iter().cloned().map(Some).collect(), &mut names);
+                    self.pure_procedures.insert(procedure.name.to_string(), procedure.clone());
+                }
+
+                return Instruction::new(Instructions::PROCEDURE(procedure), line_num, self.file.clone());
+            }
+            self.error("'procedure' statement found with unfinished definition", line_num);
+        }
+
+        /// Parses a procedure body up to its matching 'end', returning the
+        /// instructions, whether the body contains an early 'return' and
+        /// whether it contains a 'yield'.
+        fn gen_procedure_body(&mut self, line_num: u8, args: &[String]) -> (Vec<Instruction>, bool, bool) {
+            let mut instructions = Vec::new();
+            // Bindings are resolved per frame, so a body starts with none in scope
+            let outer_bindings = std::mem::take(&mut self.bindings);
+            // A procedure may be defined inside another, whose flags come back after
+            let outer_flags = (self.in_procedure, self.in_defer, self.defer_floor, self.has_return, self.has_yield, self.has_defer);
+            self.in_procedure = true;
+            self.in_defer = false;
+            self.defer_floor = None;
+            self.has_return = false;
+            self.has_yield = false;
+            self.has_defer = false;
+
+            while let Some(i) = self.operations.next() {
+                if let Some(j) = i {
+                    if j.OpCode != OpCodes::END {
+                        instructions.push(self.gen_instruction_from_op(j).unwrap());
+                    } else {
+                        self.bindings = outer_bindings;
+                        // Deferred blocks run after the body, so no call is in tail
+                        // position, and a generator's frame has to stay for 'yield'
+                        if !self.has_defer && !self.has_yield {
+                            Self::mark_tail_call(instructions.last_mut(), args);
+                        }
+                        let (returns, generator) = (self.has_return, self.has_yield);
+                        (self.in_procedure, self.in_defer, self.defer_floor, self.has_return, self.has_yield, self.has_defer) = outer_flags;
+                        return (instructions, returns, generator);
+                    }
+                }
+            }
+            self.error("'procedure' statement found without matching 'end'", line_num);
+        }
     }
 
     impl Iterator for Parser {
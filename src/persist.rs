@@ -0,0 +1,181 @@
+pub mod persist {
+    use crate::globals::globals::*;
+    use std::fs;
+    use std::io::{self, Error, ErrorKind};
+
+    /// Bumped whenever the layout below changes, so that old files are
+    /// rejected instead of misread.
+    pub const FORMAT_VERSION: u32 = 2;
+
+    const HEADER: &str = "mars-stack";
+
+    /// Writes `stacks` and their limits to `path`. Pointers between them are
+    /// stored as their position in `stacks`, held in the handle's index, and
+    /// the first stack is the one that was saved.
+    ///
+    /// ```text
+    /// mars-stack 2
+    /// stack 0 2
+    /// int 72
+    /// ptr 1
+    /// stack 1 0 : int cap 64
+    /// ```
+    pub fn write(path: &str, stacks: &[(Stack, StackLimits)]) -> io::Result<()> {
+        fs::write(path, format(stacks))
+    }
+
+    /// Reads back stacks written by `write`. Pointers still hold positions in
+    /// the returned list rather than real handles.
+    pub fn read(path: &str) -> io::Result<Vec<(Stack, StackLimits)>> {
+        parse(&fs::read_to_string(path)?)
+    }
+
+    fn format(stacks: &[(Stack, StackLimits)]) -> String {
+        let mut res = format!("{} {}\n", HEADER, FORMAT_VERSION);
+        for (id, (stack, limits)) in stacks.iter().enumerate() {
+            res.push_str(format!("stack {} {}{}\n", id, stack.len(), limits).as_str());
+            for value in stack.iter() {
+                match value {
+                    DataTypes::INT(u) => res.push_str(format!("int {}\n", u).as_str()),
+                    DataTypes::STACKPOINTER(p) => res.push_str(format!("ptr {}\n", p.index).as_str())
+                }
+            }
+        }
+        res
+    }
+
+    fn parse(text: &str) -> io::Result<Vec<(Stack, StackLimits)>> {
+        let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line.split_whitespace().collect::<Vec<_>>()));
+
+        match lines.next() {
+            Some((_, header)) if header.len() == 2 && header[0] == HEADER => {
+                let version: u32 = header[1].parse().map_err(|_| invalid(1, "invalid format version"))?;
+                if version != FORMAT_VERSION {
+                    return Err(invalid(1, format!("unsupported format version {} (expected {})", version, FORMAT_VERSION).as_str()));
+                }
+            },
+            _ => return Err(invalid(1, "not a saved stack"))
+        }
+
+        let mut stacks: Vec<(Stack, StackLimits)> = Vec::new();
+        let mut remaining = 0;
+        for (line_num, fields) in lines {
+            let value = match fields.as_slice() {
+                [] => continue,
+                ["stack", id, len, limits @ ..] if remaining == 0 => {
+                    if id.parse::<usize>().ok() != Some(stacks.len()) {
+                        return Err(invalid(line_num, "stacks are out of order"));
+                    }
+                    remaining = len.parse().map_err(|_| invalid(line_num, "invalid stack length"))?;
+                    let limits = parse_limits(limits, line_num)?;
+                    if limits.capacity.is_some_and(|capacity| remaining > capacity) {
+                        return Err(invalid(line_num, "stack holds more values than its capacity"));
+                    }
+                    stacks.push((Stack::new(), limits));
+                    continue;
+                },
+                ["int", value] if remaining > 0 => {
+                    DataTypes::INT(value.parse().map_err(|_| invalid(line_num, "invalid integer"))?)
+                },
+                ["ptr", id] if remaining > 0 => {
+                    let index = id.parse().map_err(|_| invalid(line_num, "invalid pointer"))?;
+                    DataTypes::STACKPOINTER(StackHandle {index, generation: 0})
+                },
+                _ => return Err(invalid(line_num, "unexpected line"))
+            };
+
+            let (stack, limits) = stacks.last_mut().unwrap();
+            if limits.kind.is_some_and(|kind| kind != value.kind()) {
+                return Err(invalid(line_num, format!("value does not match the stack's type '{}'", limits.kind.unwrap()).as_str()));
+            }
+            stack.push_back(value);
+            remaining -= 1;
+        }
+
+        if stacks.is_empty() || remaining > 0 {
+            return Err(invalid(text.lines().count(), "file ends early"));
+        }
+        for value in stacks.iter().flat_map(|(stack, _)| stack.iter()) {
+            if let DataTypes::STACKPOINTER(p) = value {
+                if p.index >= stacks.len() {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("pointer to missing stack {}", p.index)));
+                }
+            }
+        }
+        Ok(stacks)
+    }
+
+    /// Parses the optional ': <type>' and 'cap <n>' after a stack's length,
+    /// written the same way as in a 'spawn' statement.
+    fn parse_limits(fields: &[&str], line_num: usize) -> io::Result<StackLimits> {
+        let mut limits = StackLimits::default();
+        let mut fields = fields;
+        if let [":", kind, rest @ ..] = fields {
+            limits.kind = match *kind {
+                "int" => Some(DataKind::INT),
+                "ptr" => Some(DataKind::STACKPOINTER),
+                _ => return Err(invalid(line_num, "invalid stack type"))
+            };
+            fields = rest;
+        }
+        if let ["cap", capacity, rest @ ..] = fields {
+            limits.capacity = Some(capacity.parse().map_err(|_| invalid(line_num, "invalid stack capacity"))?);
+            fields = rest;
+        }
+        if !fields.is_empty() {
+            return Err(invalid(line_num, "unexpected line"));
+        }
+        Ok(limits)
+    }
+
+    fn invalid(line_num: usize, message: &str) -> Error {
+        Error::new(ErrorKind::InvalidData, format!("{} on line {}", message, line_num))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn int_stack(values: &[u8]) -> Stack {
+            values.iter().map(|value| DataTypes::INT(*value)).collect()
+        }
+
+        #[test]
+        fn round_trip() {
+            let limits = StackLimits {kind: Some(DataKind::INT), capacity: Some(8)};
+            let mut first = int_stack(&[72, 105]);
+            first.push_back(DataTypes::STACKPOINTER(StackHandle {index: 1, generation: 0}));
+            let stacks = vec![(first, StackLimits::default()), (int_stack(&[1, 2, 3]), limits)];
+
+            assert_eq!(parse(&format(&stacks)).unwrap(), stacks);
+        }
+
+        #[test]
+        fn rejects_other_versions() {
+            let err = parse("mars-stack 1\nstack 0 0\n").unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert_eq!(err.to_string(), format!("unsupported format version 1 (expected {}) on line 1", FORMAT_VERSION));
+        }
+
+        #[test]
+        fn rejects_dangling_pointers() {
+            let err = parse(format!("mars-stack {}\nstack 0 1\nptr 3\n", FORMAT_VERSION).as_str()).unwrap_err();
+            assert_eq!(err.to_string(), "pointer to missing stack 3");
+        }
+
+        #[test]
+        fn rejects_values_breaking_limits() {
+            let wrong_type = parse(format!("mars-stack {}\nstack 0 1 : ptr\nint 4\n", FORMAT_VERSION).as_str()).unwrap_err();
+            assert_eq!(wrong_type.to_string(), "value does not match the stack's type 'ptr' on line 3");
+
+            let over_capacity = parse(format!("mars-stack {}\nstack 0 2 cap 1\nint 4\nint 5\n", FORMAT_VERSION).as_str()).unwrap_err();
+            assert_eq!(over_capacity.to_string(), "stack holds more values than its capacity on line 2");
+        }
+
+        #[test]
+        fn rejects_truncated_files() {
+            let err = parse(format!("mars-stack {}\nstack 0 2\nint 4\n", FORMAT_VERSION).as_str()).unwrap_err();
+            assert_eq!(err.to_string(), "file ends early on line 3");
+        }
+    }
+}